@@ -13,14 +13,22 @@ import { canisterId, createActor } from '../../../declarations/canister';
 import { Carrier } from 'src/carriers/entities/carrier.entity';
 import { Shipper } from '../auth/entities/shipper.entity';
 import { Principal } from '@dfinity/principal';
+import { Ed25519KeyIdentity } from '@dfinity/identity';
 import { Address } from './entities/address.entity';
 import { ShipmentSequence } from './entities/shipment-sequence.entity';
 import { NotificationService } from '../core/services/notification.service';
 
 const host = `http://localhost:4943`;
 
-export const anonymousBackend = createActor(canisterId, {
-  agentOptions: { host },
+// getEvents only returns every event to admins and auditors, so the sync runs
+// as an identity that has been added with `addAuditor`.
+const syncSeed = process.env.CANISTER_SYNC_IDENTITY_SEED;
+const syncIdentity = syncSeed
+  ? Ed25519KeyIdentity.generate(new Uint8Array(Buffer.from(syncSeed, 'hex')))
+  : undefined;
+
+export const syncBackend = createActor(canisterId, {
+  agentOptions: { host, identity: syncIdentity },
 });
 
 export function isCreatedEvent(
@@ -101,7 +109,7 @@ export class ShipmentsSyncService {
       const lastProcessedSequence = await this.getLastProcessedSequence();
 
       // Get new events from canister
-      const events = await anonymousBackend.getEvents([lastProcessedSequence]);
+      const events = await syncBackend.getEvents([lastProcessedSequence]);
       this.logger.debug(`Received ${events.length} events from canister`);
 
       for (const timestampedEvent of events) {
@@ -186,7 +194,7 @@ export class ShipmentsSyncService {
     if (!shipment) {
       this.logger.debug('Shipment not found, fetching from canister...');
       // Fetch full shipment data and create
-      const canisterShipmentOpt = await anonymousBackend.getShipment(
+      const canisterShipmentOpt = await syncBackend.getShipment(
         event.Created.shipment_id,
       );
       this.logger.debug(`Canister shipment data`);
@@ -0,0 +1 @@
+//! Only hosts the PocketIC integration tests in `tests/`.
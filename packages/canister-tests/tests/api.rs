@@ -0,0 +1,428 @@
+mod common;
+
+use candid::Principal;
+use common::{
+    principal, shipment_info, ExpiryConfig, Harness, LocationPoint, OperationalMode,
+    ProofOfDelivery, Shipment, ShipmentStatus, TimestampedEvent, HASHED_SECRET, SECRET,
+};
+use std::time::Duration;
+
+#[test]
+fn test_create_buy_finalize() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let shipment = harness.get_shipment(shipment_id).unwrap();
+    assert_eq!(shipment.status, ShipmentStatus::Pending);
+    assert_eq!(shipment.customer, customer);
+
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+    let shipment = harness.get_shipment(shipment_id).unwrap();
+    assert_eq!(shipment.status, ShipmentStatus::Bought);
+    assert_eq!(shipment.carrier, Some(carrier));
+
+    let result: Result<(), String> = harness.update(
+        carrier,
+        "pickupShipment",
+        (shipment_id, None::<LocationPoint>),
+    );
+    result.unwrap();
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::InTransit
+    );
+
+    let result: Result<(), String> = harness.update(
+        carrier,
+        "finalizeShipment",
+        (
+            shipment_id,
+            Some(SECRET.to_string()),
+            None::<ProofOfDelivery>,
+        ),
+    );
+    result.unwrap();
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::Delivered
+    );
+}
+
+#[test]
+fn test_authorization_failures() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+    let stranger = principal(3);
+
+    assert!(harness.is_rejected(
+        Principal::anonymous(),
+        "createShipment",
+        (
+            "parcel".to_string(),
+            HASHED_SECRET.to_string(),
+            shipment_info(),
+        ),
+    ));
+    assert!(harness.is_rejected(
+        customer,
+        "createShipment",
+        (
+            "parcel".to_string(),
+            "not a hash".to_string(),
+            shipment_info()
+        ),
+    ));
+
+    let result = harness.create_shipment(customer);
+    assert_eq!(result.unwrap_err(), "Customer not registered");
+
+    harness.register_customer(customer);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    assert_eq!(result.unwrap_err(), "Carrier not registered");
+
+    harness.register_carrier(carrier);
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let result: Result<(), String> = harness.update(
+        stranger,
+        "finalizeShipment",
+        (
+            shipment_id,
+            Some(SECRET.to_string()),
+            None::<ProofOfDelivery>,
+        ),
+    );
+    assert!(result.is_err());
+
+    let result: Result<(), String> = harness.update(
+        carrier,
+        "finalizeShipment",
+        (
+            shipment_id,
+            Some("wrong".to_string()),
+            None::<ProofOfDelivery>,
+        ),
+    );
+    assert!(result.is_err());
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::Bought
+    );
+
+    let result: Result<(), String> =
+        harness.update(stranger, "setExpiryConfig", (ExpiryConfig::default(),));
+    assert_eq!(result.unwrap_err(), "Cannot be called by non-admins");
+
+    let result: Result<(), String> =
+        harness.update(harness.admin, "setExpiryConfig", (ExpiryConfig::default(),));
+    result.unwrap();
+}
+
+#[test]
+fn test_event_sequencing() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let before = harness.events(None);
+
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let events = harness.events(None);
+    assert!(events.len() >= before.len() + 2);
+    assert!(events
+        .windows(2)
+        .all(|pair| pair[0].sequence < pair[1].sequence && pair[0].timestamp <= pair[1].timestamp));
+
+    let anonymous: Vec<TimestampedEvent> =
+        harness.query(Principal::anonymous(), "getEvents", (None::<u64>,));
+    assert!(anonymous.is_empty());
+
+    let last = before.last().map(|event| event.sequence);
+    let newer = harness.events(last);
+    assert_eq!(newer.len(), events.len() - before.len());
+    assert!(newer
+        .iter()
+        .all(|event| last.map_or(true, |last| event.sequence > last)));
+}
+
+#[test]
+fn test_upgrade_preserves_state() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let events = harness.events(None);
+    harness.upgrade();
+
+    let shipment = harness.get_shipment(shipment_id).unwrap();
+    assert_eq!(shipment.status, ShipmentStatus::Bought);
+    assert_eq!(shipment.carrier, Some(carrier));
+    assert_eq!(harness.events(None).len(), events.len());
+
+    // Registrations, admin rights and the id counter survive as well.
+    let next_id = harness.create_shipment(customer).unwrap();
+    assert!(next_id > shipment_id);
+
+    let result: Result<(), String> =
+        harness.update(harness.admin, "setExpiryConfig", (ExpiryConfig::default(),));
+    result.unwrap();
+
+    let after = harness.events(None);
+    assert!(after.last().unwrap().sequence > events.last().unwrap().sequence);
+}
+
+#[test]
+fn test_timers_expire_pending_shipments() {
+    let harness = Harness::new();
+    let customer = principal(1);
+
+    let config = ExpiryConfig {
+        pending_ttl_secs: Some(120),
+        ..Default::default()
+    };
+    let result: Result<(), String> = harness.update(harness.admin, "setExpiryConfig", (config,));
+    result.unwrap();
+
+    harness.register_customer(customer);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+
+    harness.advance(Duration::from_secs(61));
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::Pending
+    );
+
+    harness.advance(Duration::from_secs(120));
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::Cancelled
+    );
+}
+
+#[test]
+fn test_timers_keep_running_after_upgrade() {
+    let harness = Harness::new();
+    let customer = principal(1);
+
+    let config = ExpiryConfig {
+        pending_ttl_secs: Some(60),
+        ..Default::default()
+    };
+    let result: Result<(), String> = harness.update(harness.admin, "setExpiryConfig", (config,));
+    result.unwrap();
+
+    harness.register_customer(customer);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+
+    harness.upgrade();
+    harness.advance(Duration::from_secs(180));
+
+    assert_eq!(
+        harness.get_shipment(shipment_id).unwrap().status,
+        ShipmentStatus::Cancelled
+    );
+}
+
+#[test]
+fn test_read_access() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let auditor = principal(4);
+
+    harness.register_customer(customer);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+
+    // Anyone still sees the public listing.
+    let shipment = harness.get_shipment(shipment_id).unwrap();
+    assert_eq!(shipment.customer, customer);
+
+    let result: Result<Vec<Shipment>, String> =
+        harness.query(Principal::anonymous(), "shipments", ());
+    assert_eq!(result.unwrap_err(), "Cannot be called by non-auditors");
+
+    let result: Result<(), String> = harness.update(harness.admin, "addAuditor", (auditor,));
+    result.unwrap();
+
+    let result: Result<Vec<Shipment>, String> = harness.query(auditor, "shipments", ());
+    assert!(result
+        .unwrap()
+        .iter()
+        .any(|shipment| shipment.id == shipment_id));
+}
+
+#[test]
+fn test_failed_finalize_attempts_are_limited() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let finalize = |secret: &str| -> Result<(), String> {
+        harness.update(
+            carrier,
+            "finalizeShipment",
+            (
+                shipment_id,
+                Some(secret.to_string()),
+                None::<ProofOfDelivery>,
+            ),
+        )
+    };
+
+    for _ in 0..5 {
+        assert!(finalize("wrong").is_err());
+    }
+
+    // Locked out even with the right secret until the hour has passed.
+    assert_eq!(
+        finalize(SECRET).unwrap_err(),
+        "Too many failed finalize attempts, try again later"
+    );
+}
+
+#[test]
+fn test_all_or_nothing_batches_cannot_bypass_the_finalize_quota() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let guesses = vec![
+        (
+            shipment_id,
+            Some("wrong".to_string()),
+            None::<ProofOfDelivery>
+        );
+        10
+    ];
+    for _ in 0..5 {
+        let result: Result<Vec<Result<(), String>>, String> =
+            harness.update(carrier, "finalizeShipments", (guesses.clone(), true));
+        assert_eq!(
+            result.unwrap_err(),
+            "Batch item 0 failed: secret verification failed"
+        );
+    }
+
+    let result: Result<Vec<Result<(), String>>, String> =
+        harness.update(carrier, "finalizeShipments", (guesses, true));
+    assert_eq!(
+        result.unwrap_err(),
+        "Batch item 0 failed: Too many failed finalize attempts, try again later"
+    );
+}
+
+#[test]
+fn test_operational_modes() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    result.unwrap();
+
+    let set_mode = |mode: OperationalMode| {
+        let result: Result<(), String> = harness.update(harness.admin, "setMode", (mode,));
+        result.unwrap();
+    };
+
+    set_mode(OperationalMode::MarketplacePaused);
+    assert_eq!(
+        harness.create_shipment(customer).unwrap_err(),
+        "Marketplace is paused"
+    );
+    let result: Result<(), String> = harness.update(
+        carrier,
+        "pickupShipment",
+        (shipment_id, None::<LocationPoint>),
+    );
+    result.unwrap();
+
+    set_mode(OperationalMode::ReadOnly);
+    let result: Result<(), String> = harness.update(
+        carrier,
+        "finalizeShipment",
+        (
+            shipment_id,
+            Some(SECRET.to_string()),
+            None::<ProofOfDelivery>,
+        ),
+    );
+    assert_eq!(result.unwrap_err(), "Canister is read-only for maintenance");
+    assert!(harness.get_shipment(shipment_id).is_some());
+
+    set_mode(OperationalMode::Running);
+    let mode: OperationalMode = harness.query(customer, "getMode", ());
+    assert_eq!(mode, OperationalMode::Running);
+    harness.create_shipment(customer).unwrap();
+}
+
+#[test]
+fn test_admin_interventions() {
+    let harness = Harness::new();
+    let customer = principal(1);
+    let carrier = principal(2);
+
+    harness.register_customer(customer);
+    harness.register_carrier(carrier);
+    let shipment_id = harness.create_shipment(customer).unwrap();
+
+    let result: Result<(), String> = harness.update(
+        harness.admin,
+        "removeShipment",
+        (shipment_id, "".to_string()),
+    );
+    assert_eq!(result.unwrap_err(), "Reason cannot be empty");
+    let result: Result<(), String> =
+        harness.update(customer, "banPrincipal", (carrier, "spam".to_string()));
+    assert_eq!(result.unwrap_err(), "Cannot be called by non-admins");
+
+    let result: Result<(), String> =
+        harness.update(harness.admin, "banPrincipal", (carrier, "spam".to_string()));
+    result.unwrap();
+    let result: Result<(), String> = harness.update(carrier, "buyShipment", (shipment_id,));
+    assert_eq!(result.unwrap_err(), "Caller is banned");
+
+    let result: Result<(), String> = harness.update(
+        harness.admin,
+        "removeShipment",
+        (shipment_id, "abusive listing".to_string()),
+    );
+    result.unwrap();
+    let shipment = harness.get_shipment(shipment_id).unwrap();
+    assert_eq!(shipment.status, ShipmentStatus::Cancelled);
+}
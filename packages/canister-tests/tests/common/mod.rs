@@ -0,0 +1,293 @@
+//! Harness installing the canister wasm into a local PocketIC instance.
+//!
+//! Build the wasm with `./build.sh canister` first and point `POCKET_IC_BIN`
+//! at a PocketIC server binary. Set `CANISTER_WASM` to test another build.
+
+#![allow(dead_code)]
+
+use candid::{decode_one, encode_args, utils::ArgumentEncoder, CandidType, Principal};
+use pocket_ic::{PocketIc, WasmResult};
+use serde::Deserialize;
+use std::time::Duration;
+
+const DEFAULT_WASM: &str = "../../target/wasm32-unknown-unknown/release/canister.wasm";
+const INIT_CYCLES: u128 = 2_000_000_000_000;
+
+pub const SECRET: &str = "secret";
+pub const HASHED_SECRET: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+// Only the fields the tests need, candid skips the rest when decoding and
+// fills omitted optional fields with `null` when encoding.
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Profile {
+    pub display_name: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShipmentLocation {
+    pub street: String,
+    pub lat: f64,
+    pub lng: f64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum SizeCategory {
+    Envelope,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum VehicleClass {
+    Car,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Vehicle {
+    pub class: VehicleClass,
+    pub cargo_width: u64,
+    pub cargo_height: u64,
+    pub cargo_depth: u64,
+    pub max_load_grams: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Handling {
+    pub fragile: bool,
+    pub hazardous: bool,
+    pub perishable: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ShipmentInfo {
+    pub value: u64,
+    pub price: u64,
+    pub source: ShipmentLocation,
+    pub destination: ShipmentLocation,
+    pub size_category: SizeCategory,
+    pub weight_grams: u64,
+    pub handling: Handling,
+    pub handoff_points: Vec<ShipmentLocation>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LocationPoint {
+    pub lat: f64,
+    pub lng: f64,
+    pub accuracy_m: f64,
+    pub reported_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProofOfDelivery {
+    pub captured_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ShipmentStatus {
+    Pending,
+    Bought,
+    InTransit,
+    Delivered,
+    Disputed,
+    Cancelled,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Shipment {
+    pub id: u64,
+    pub status: ShipmentStatus,
+    pub customer: Principal,
+    pub carrier: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TimestampedEvent {
+    pub timestamp: u64,
+    pub sequence: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ExpiryConfig {
+    pub pending_ttl_secs: Option<u64>,
+    pub pickup_timeout_secs: Option<u64>,
+    pub abandonment_penalty_bps: u16,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OperationalMode {
+    Running,
+    ReadOnly,
+    MarketplacePaused,
+}
+
+pub fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id, 0xAA])
+}
+
+/// No ledger runs next to the canister, so carriers cannot deposit collateral
+/// and the shipment is worth nothing to lock.
+pub fn shipment_info() -> ShipmentInfo {
+    ShipmentInfo {
+        value: 0,
+        price: 10,
+        source: ShipmentLocation {
+            street: "Marszałkowska 1".to_string(),
+            lat: 52.2297,
+            lng: 21.0122,
+        },
+        destination: ShipmentLocation {
+            street: "Floriańska 1".to_string(),
+            lat: 50.0647,
+            lng: 19.945,
+        },
+        size_category: SizeCategory::Envelope,
+        weight_grams: 100,
+        handling: Handling::default(),
+        handoff_points: vec![],
+    }
+}
+
+pub struct Harness {
+    pub pic: PocketIc,
+    pub canister_id: Principal,
+    pub admin: Principal,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        let pic = PocketIc::new();
+        let admin = principal(0);
+
+        let canister_id = pic.create_canister_with_settings(Some(admin), None);
+        pic.add_cycles(canister_id, INIT_CYCLES);
+        pic.install_canister(canister_id, wasm(), encode_args(()).unwrap(), Some(admin));
+
+        Self {
+            pic,
+            canister_id,
+            admin,
+        }
+    }
+
+    pub fn update<R>(&self, sender: Principal, method: &str, args: impl ArgumentEncoder) -> R
+    where
+        R: CandidType + for<'de> Deserialize<'de>,
+    {
+        let result = self
+            .pic
+            .update_call(self.canister_id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{method} failed: {e:?}"));
+
+        decode_reply(method, result)
+    }
+
+    /// Whether the call is rejected before reaching the endpoint, e.g. by
+    /// `inspect_message`.
+    pub fn is_rejected(&self, sender: Principal, method: &str, args: impl ArgumentEncoder) -> bool {
+        self.pic
+            .update_call(self.canister_id, sender, method, encode_args(args).unwrap())
+            .is_err()
+    }
+
+    pub fn query<R>(&self, sender: Principal, method: &str, args: impl ArgumentEncoder) -> R
+    where
+        R: CandidType + for<'de> Deserialize<'de>,
+    {
+        let result = self
+            .pic
+            .query_call(self.canister_id, sender, method, encode_args(args).unwrap())
+            .unwrap_or_else(|e| panic!("{method} failed: {e:?}"));
+
+        decode_reply(method, result)
+    }
+
+    pub fn upgrade(&self) {
+        self.pic
+            .upgrade_canister(
+                self.canister_id,
+                wasm(),
+                encode_args(()).unwrap(),
+                Some(self.admin),
+            )
+            .expect("upgrade failed");
+    }
+
+    /// Moves the clock forward and lets the canister run its due timers.
+    pub fn advance(&self, duration: Duration) {
+        self.pic.advance_time(duration);
+        for _ in 0..3 {
+            self.pic.tick();
+        }
+    }
+
+    pub fn register_customer(&self, customer: Principal) {
+        let result: Result<(), String> = self.update(
+            customer,
+            "registerCustomer",
+            (Profile {
+                display_name: "Customer".to_string(),
+            },),
+        );
+        result.unwrap();
+    }
+
+    /// Registers a carrier with a car, so it has room for the fixture's
+    /// envelopes.
+    pub fn register_carrier(&self, carrier: Principal) {
+        let result: Result<(), String> = self.update(
+            carrier,
+            "registerCarrier",
+            (Profile {
+                display_name: "Carrier".to_string(),
+            },),
+        );
+        result.unwrap();
+
+        let car = Vehicle {
+            class: VehicleClass::Car,
+            cargo_width: 100,
+            cargo_height: 50,
+            cargo_depth: 100,
+            max_load_grams: 300_000,
+        };
+        let result: Result<(), String> = self.update(carrier, "setVehicles", (vec![car],));
+        result.unwrap();
+    }
+
+    pub fn create_shipment(&self, customer: Principal) -> Result<u64, String> {
+        self.update(
+            customer,
+            "createShipment",
+            (
+                "parcel".to_string(),
+                HASHED_SECRET.to_string(),
+                shipment_info(),
+            ),
+        )
+    }
+
+    pub fn get_shipment(&self, shipment_id: u64) -> Option<Shipment> {
+        self.query(Principal::anonymous(), "getShipment", (shipment_id,))
+    }
+
+    /// Every event, read as the admin since others only see their own.
+    pub fn events(&self, since_sequence: Option<u64>) -> Vec<TimestampedEvent> {
+        self.query(self.admin, "getEvents", (since_sequence,))
+    }
+}
+
+fn wasm() -> Vec<u8> {
+    let path = std::env::var("CANISTER_WASM").unwrap_or_else(|_| DEFAULT_WASM.to_string());
+
+    std::fs::read(&path).unwrap_or_else(|e| panic!("cannot read wasm at {path}: {e}"))
+}
+
+fn decode_reply<R>(method: &str, result: WasmResult) -> R
+where
+    R: CandidType + for<'de> Deserialize<'de>,
+{
+    match result {
+        WasmResult::Reply(bytes) => decode_one(&bytes).unwrap(),
+        WasmResult::Reject(message) => panic!("{method} was rejected: {message}"),
+    }
+}
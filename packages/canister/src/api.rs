@@ -0,0 +1,1574 @@
+//! Logic behind the endpoints exported in `lib.rs`. It takes the environment
+//! as an argument, so that whole flows run under plain `cargo test`.
+
+use crate::{
+    env::Environment,
+    jobs,
+    metrics::Metrics,
+    models::{
+        carrier::Carrier,
+        collateral::CollateralConfig,
+        dispute::{Dispute, DisputeConfig, DisputeOutcome, MAX_REASON_LENGTH},
+        expiry::ExpiryConfig,
+        fee::{FeeConfig, FeeLedger},
+        geofence::GeofenceConfig,
+        mode::OperationalMode,
+        pricing::PricingConfig,
+        profile::Profile,
+        proof::ProofOfDelivery,
+        quota::QuotaConfig,
+        reputation::Rating,
+        settlement::Settlement,
+        shipment::{Shipment, ShipmentInfo, ShipmentStatus},
+        shipment_id::{ShipmentId, ShipmentIdInner},
+        sla::SlaConfig,
+        tracking::LocationPoint,
+        vehicle::Vehicle,
+    },
+    processing::{self, Operation, ShipmentGuard},
+    record_event, record_settlement,
+    state::{
+        self, transaction, CARRIERS, COLLATERAL_CONFIG, CUSTOMERS, DISPUTE_CONFIG, EXPIRY_CONFIG,
+        FEE_CONFIG, FEE_LEDGER, GEOFENCE_CONFIG, MODE, PRICING_CONFIG, QUOTA_CONFIG, SHIPMENTS,
+        SLA_CONFIG, TRAILS, USAGE,
+    },
+    AccountProfiles, AdminAction, DisputeCase, NewShipment, ShipmentEvent, TimestampedEvent,
+    Tracking, ADMINS, ARBITERS, AUDITORS, BANNED, EVENTS, MAX_EVENTS_AGE,
+};
+use anyhow::anyhow;
+use candid::Principal;
+
+const MAX_BATCH_SIZE: usize = 50;
+
+fn check_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        return Err("Cannot be called anonymously".to_string());
+    }
+
+    Ok(())
+}
+
+fn check_admin(caller: Principal) -> Result<(), String> {
+    if !ADMINS.with_borrow(|admins| admins.contains(&caller)) {
+        return Err("Cannot be called by non-admins".to_string());
+    }
+
+    Ok(())
+}
+
+fn check_arbiter(caller: Principal) -> Result<(), String> {
+    if check_admin(caller).is_err() && !ARBITERS.with_borrow(|arbiters| arbiters.contains(&caller))
+    {
+        return Err("Cannot be called by non-arbiters".to_string());
+    }
+
+    Ok(())
+}
+
+fn check_not_banned(caller: Principal) -> Result<(), String> {
+    if BANNED.with_borrow(|banned| banned.contains(&caller)) {
+        return Err("Caller is banned".to_string());
+    }
+
+    Ok(())
+}
+
+fn check_reason(reason: &str) -> Result<(), String> {
+    if reason.trim().is_empty() {
+        return Err("Reason cannot be empty".to_string());
+    }
+
+    if reason.len() > MAX_REASON_LENGTH {
+        return Err(format!(
+            "Reason cannot exceed {MAX_REASON_LENGTH} characters"
+        ));
+    }
+
+    Ok(())
+}
+
+fn check_writable() -> Result<(), String> {
+    MODE.with_borrow(|mode| mode.check_write())
+        .map_err(|e| e.to_string())
+}
+
+fn check_marketplace_open() -> Result<(), String> {
+    MODE.with_borrow(|mode| mode.check_marketplace())
+        .map_err(|e| e.to_string())
+}
+
+/// Auditors get read-only access to everything admins can read.
+fn check_auditor(caller: Principal) -> Result<(), String> {
+    if check_admin(caller).is_err() && !AUDITORS.with_borrow(|auditors| auditors.contains(&caller))
+    {
+        return Err("Cannot be called by non-auditors".to_string());
+    }
+
+    Ok(())
+}
+
+/// Relation of a caller to a shipment, deciding what of it they may read.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Customer,
+    Carrier,
+    Recipient,
+    Admin,
+    Auditor,
+}
+
+const SHIPMENT_READERS: &[Role] = &[
+    Role::Customer,
+    Role::Carrier,
+    Role::Recipient,
+    Role::Admin,
+    Role::Auditor,
+];
+const TRACKING_READERS: &[Role] = &[
+    Role::Customer,
+    Role::Carrier,
+    Role::Recipient,
+    Role::Admin,
+    Role::Auditor,
+];
+/// Arbiters can read dispute cases as well, see `get_dispute_case`.
+const DISPUTE_READERS: &[Role] = &[Role::Customer, Role::Carrier, Role::Admin, Role::Auditor];
+
+fn shipment_roles(caller: Principal, shipment: &Shipment) -> Vec<Role> {
+    let mut roles = vec![];
+
+    if caller == shipment.customer_id() {
+        roles.push(Role::Customer);
+    }
+    if shipment.involves_carrier(caller) {
+        roles.push(Role::Carrier);
+    }
+    if shipment.recipient_id() == Some(caller) {
+        roles.push(Role::Recipient);
+    }
+    if ADMINS.with_borrow(|admins| admins.contains(&caller)) {
+        roles.push(Role::Admin);
+    }
+    if AUDITORS.with_borrow(|auditors| auditors.contains(&caller)) {
+        roles.push(Role::Auditor);
+    }
+
+    roles
+}
+
+fn check_read(caller: Principal, shipment: &Shipment, policy: &[Role]) -> Result<(), String> {
+    if !shipment_roles(caller, shipment)
+        .iter()
+        .any(|role| policy.contains(role))
+    {
+        return Err("Caller is not a party of the shipment".to_string());
+    }
+
+    Ok(())
+}
+
+/// Full details for those allowed to read the shipment, the public listing
+/// for everyone else.
+fn shipment_view(caller: Principal, shipment: &Shipment) -> Shipment {
+    match check_read(caller, shipment, SHIPMENT_READERS) {
+        Ok(()) => shipment.clone(),
+        Err(_) => shipment.redacted(),
+    }
+}
+
+pub fn finalize_shipment(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    secret_key: Option<String>,
+    proof: Option<ProofOfDelivery>,
+) -> Result<(), String> {
+    check_writable()?;
+
+    finalize_one(env, shipment_id, secret_key, proof)
+}
+
+pub fn finalize_shipments(
+    env: &impl Environment,
+    items: Vec<(ShipmentIdInner, Option<String>, Option<ProofOfDelivery>)>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<(), String>>, String> {
+    check_writable()?;
+
+    // A trap rolls back the failed attempts recorded for the quota, so wrong
+    // secrets have to be caught before the batch can trap.
+    if all_or_nothing {
+        for (index, (shipment_id, secret_key, _)) in items.iter().enumerate() {
+            check_finalize_secret(env, *shipment_id, secret_key.clone())
+                .map_err(|e| format!("Batch item {index} failed: {e}"))?;
+        }
+    }
+
+    run_batch(items, all_or_nothing, |(shipment_id, secret_key, proof)| {
+        finalize_one(env, shipment_id, secret_key, proof)
+    })
+}
+
+/// Checks the caller's finalize quota and the secret. Only a wrong secret
+/// counts as a failed finalize attempt, nothing else is written.
+fn check_finalize_secret(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    secret_key: Option<String>,
+) -> Result<(), String> {
+    let caller = env.caller();
+    let now = env.now();
+
+    let quotas = QUOTA_CONFIG.with_borrow(|config| config.clone());
+    USAGE
+        .with_borrow_mut(|usage| {
+            let usage = usage.entry(caller).or_default();
+            usage.check_finalize(&quotas, now)
+        })
+        .map_err(|e| e.to_string())?;
+
+    let checked = SHIPMENTS.with_borrow(|shipments| {
+        shipments
+            .get(&shipment_id)
+            .map(|shipment| shipment.check_secret(caller, secret_key))
+    });
+
+    match checked {
+        None => Err("Shipment not found".to_string()),
+        Some(Err(e)) => {
+            USAGE.with_borrow_mut(|usage| {
+                let usage = usage.entry(caller).or_default();
+                usage.record_failed_finalize(now);
+            });
+
+            Err(e.to_string())
+        }
+        Some(Ok(())) => Ok(()),
+    }
+}
+
+fn finalize_one(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    secret_key: Option<String>,
+    proof: Option<ProofOfDelivery>,
+) -> Result<(), String> {
+    let caller = env.caller();
+    let now = env.now();
+
+    jobs::enforce_sla(env, shipment_id);
+
+    // Rejections for any other reason, e.g. a bad position fix, do not count
+    // against the caller.
+    check_finalize_secret(env, shipment_id, secret_key.clone())?;
+
+    let guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Finalize).map_err(|e| e.to_string())?;
+
+    transaction(|tx| {
+        let (shipment, carrier, customer) = tx.parties(shipment_id)?;
+
+        let carrier_id = carrier.id();
+        if caller != carrier_id && caller != shipment.customer_id() {
+            return Err(anyhow!("Caller is not a party of the shipment"));
+        }
+
+        // The customer finalizing is the confirmation the geofence asks for.
+        if caller == carrier_id {
+            let position = proof.as_ref().and_then(|proof| proof.position.as_ref());
+            GEOFENCE_CONFIG.with_borrow(|config| {
+                config.check_delivery(shipment.info().destination(), position)
+            })?;
+        }
+
+        shipment.finalize(carrier, customer, secret_key, proof, caller, now)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(env, ShipmentEvent::Finalized { shipment_id });
+
+    drop(guard);
+    jobs::settle(env, shipment_id);
+
+    Ok(())
+}
+
+pub fn open_dispute(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    reason: String,
+    evidence_hash: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_anonymous(caller)?;
+
+    let now = env.now();
+    let window = DISPUTE_CONFIG.with_borrow(|config| config.window());
+    let dispute = Dispute::new(caller, reason.clone(), evidence_hash.clone(), now)
+        .map_err(|e| e.to_string())?;
+
+    transaction(|tx| {
+        let (shipment, carrier, customer) = tx.parties(shipment_id)?;
+        shipment.open_dispute(carrier, customer, dispute, window, now)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::DisputeOpened {
+            shipment_id,
+            opened_by: caller,
+            reason,
+            evidence_hash,
+        },
+    );
+
+    Ok(())
+}
+
+/// Rates the other side of a delivered shipment. A customer names the carrier
+/// of the leg it rates, and rates the carrier that delivered if it does not.
+pub fn rate_shipment(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    score: u8,
+    tags: Vec<String>,
+    carrier: Option<Principal>,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_anonymous(caller)?;
+
+    let rating = Rating::new(score, tags, caller, env.now()).map_err(|e| e.to_string())?;
+
+    let rated = transaction(|tx| {
+        let shipment = tx
+            .shipment(shipment_id)
+            .ok_or(anyhow!("Shipment not found"))?;
+        let customer_id = shipment.customer_id();
+        let carrier_id = match caller == customer_id {
+            true => carrier
+                .or(shipment.carrier_id())
+                .ok_or(anyhow!("Carrier not set"))?,
+            false => caller,
+        };
+
+        let (shipment, carrier, customer) = tx.parties_with_carrier(shipment_id, carrier_id)?;
+        shipment.rate(carrier, customer, rating)?;
+
+        Ok(match caller == customer_id {
+            true => carrier_id,
+            false => customer_id,
+        })
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::Rated {
+            shipment_id,
+            rated_by: caller,
+            rated,
+            score,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn set_recipient(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    recipient: Option<Principal>,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_anonymous(caller)?;
+
+    transaction(|tx| {
+        let shipment = tx
+            .shipment(shipment_id)
+            .ok_or(anyhow!("Shipment not found"))?;
+        shipment.set_recipient(caller, recipient)
+    })
+    .map_err(|e| e.to_string())
+}
+
+pub fn resolve_dispute(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    outcome: DisputeOutcome,
+) -> Result<Settlement, String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_arbiter(caller)?;
+
+    let _guard = ShipmentGuard::acquire(env, shipment_id, Operation::ResolveDispute)
+        .map_err(|e| e.to_string())?;
+
+    let fees = FEE_CONFIG.with_borrow(|config| config.clone());
+    let settlement = transaction(|tx| {
+        let (shipment, carrier, _) = tx.parties(shipment_id)?;
+        shipment.resolve_dispute(carrier, caller, outcome.clone(), &fees)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::DisputeResolved {
+            shipment_id,
+            arbiter: caller,
+            outcome,
+        },
+    );
+    record_settlement(env, shipment_id, None, settlement.clone());
+
+    Ok(settlement)
+}
+
+pub fn get_dispute_case(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+) -> Result<DisputeCase, String> {
+    let caller = env.caller();
+
+    let (dispute, proof_of_delivery) = SHIPMENTS.with_borrow(|shipments| {
+        let shipment = shipments
+            .get(&shipment_id)
+            .ok_or("Shipment not found".to_string())?;
+
+        check_read(caller, shipment, DISPUTE_READERS).or_else(|_| check_arbiter(caller))?;
+
+        let dispute = shipment
+            .dispute()
+            .cloned()
+            .ok_or("Shipment has no dispute".to_string())?;
+
+        Ok::<_, String>((dispute, shipment.proof_of_delivery().cloned()))
+    })?;
+
+    let trail = TRAILS.with_borrow(|trails| {
+        trails
+            .get(&shipment_id)
+            .map(|trail| trail.points())
+            .unwrap_or_default()
+    });
+
+    Ok(DisputeCase {
+        dispute,
+        proof_of_delivery,
+        trail,
+    })
+}
+
+pub fn buy_shipment(env: &impl Environment, shipment_id: ShipmentIdInner) -> Result<(), String> {
+    check_marketplace_open()?;
+
+    buy_with(env, shipment_id, |shipment, carrier, now| {
+        shipment.buy(carrier, now)
+    })
+}
+
+pub fn buy_shipments(
+    env: &impl Environment,
+    shipment_ids: Vec<ShipmentIdInner>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<(), String>>, String> {
+    check_marketplace_open()?;
+
+    run_batch(shipment_ids, all_or_nothing, |shipment_id| {
+        buy_with(env, shipment_id, |shipment, carrier, now| {
+            shipment.buy(carrier, now)
+        })
+    })
+}
+
+pub fn buy_shipment_leg(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    leg: u32,
+) -> Result<(), String> {
+    check_marketplace_open()?;
+
+    buy_with(env, shipment_id, |shipment, carrier, now| {
+        shipment.buy_leg(carrier, leg, now)
+    })
+}
+
+/// Runs a purchase for the calling carrier and emits the assignment events.
+fn buy_with(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    buy: impl FnOnce(&mut Shipment, &mut Carrier, u64) -> anyhow::Result<()>,
+) -> Result<(), String> {
+    let carrier_id = env.caller();
+    check_anonymous(carrier_id)?;
+    check_not_banned(carrier_id)?;
+    let now = env.now();
+
+    let _guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Buy).map_err(|e| e.to_string())?;
+
+    let (active, leg) = transaction(|tx| {
+        tx.carrier(carrier_id)
+            .ok_or(anyhow!("Carrier not registered"))?;
+        let (shipment, carrier) = tx.shipment_with_carrier(shipment_id, carrier_id)?;
+
+        buy(shipment, carrier, now)?;
+
+        let leg = shipment
+            .legs()
+            .iter()
+            .position(|leg| leg.carrier() == Some(carrier_id));
+
+        Ok((shipment.carrier_id() == Some(carrier_id), leg))
+    })
+    .map_err(|e| e.to_string())?;
+
+    if active {
+        record_event(
+            env,
+            ShipmentEvent::CarrierAssigned {
+                shipment_id,
+                carrier: carrier_id,
+            },
+        );
+    }
+
+    if let Some(leg) = leg {
+        record_event(
+            env,
+            ShipmentEvent::LegAssigned {
+                shipment_id,
+                leg: leg as u32,
+                carrier: carrier_id,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+pub fn pickup_shipment(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    position: Option<LocationPoint>,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_anonymous(caller)?;
+    let now = env.now();
+
+    let _guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Pickup).map_err(|e| e.to_string())?;
+
+    transaction(|tx| {
+        let shipment = tx
+            .shipment(shipment_id)
+            .ok_or(anyhow!("Shipment not found"))?;
+
+        if shipment.carrier_id() == Some(caller) {
+            GEOFENCE_CONFIG.with_borrow(|config| {
+                config.check_pickup(shipment.info().source(), position.as_ref())
+            })?;
+        }
+
+        shipment.pickup(caller, now)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::StatusUpdated {
+            shipment_id,
+            status: ShipmentStatus::InTransit,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn confirm_handoff(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+) -> Result<Settlement, String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_anonymous(caller)?;
+    let now = env.now();
+
+    jobs::enforce_sla(env, shipment_id);
+
+    let _guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Handoff).map_err(|e| e.to_string())?;
+
+    let fees = FEE_CONFIG.with_borrow(|config| config.clone());
+    let (previous_id, leg, settlement) = transaction(|tx| {
+        let (shipment, previous, _) = tx.parties(shipment_id)?;
+        let previous_id = previous.id();
+        let leg = shipment.current_leg();
+
+        let settlement = shipment.confirm_handoff(previous, caller, &fees, now)?;
+
+        Ok((previous_id, leg, settlement))
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::HandoffConfirmed {
+            shipment_id,
+            leg,
+            from: previous_id,
+            to: caller,
+        },
+    );
+    record_settlement(env, shipment_id, Some(leg), settlement.clone());
+
+    Ok(settlement)
+}
+
+pub fn report_location(
+    env: &impl Environment,
+    lat: f64,
+    lng: f64,
+    accuracy: f64,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let carrier_id = env.caller();
+    check_anonymous(carrier_id)?;
+
+    let point = LocationPoint {
+        lat,
+        lng,
+        accuracy_m: accuracy,
+        reported_at: env.now(),
+    };
+    point.validate().map_err(|e| e.to_string())?;
+
+    let active =
+        SHIPMENTS.with_borrow(|shipments| shipments.get_active_ids_for_carrier(&carrier_id));
+    if active.is_empty() {
+        return Err("No active shipments to report a location for".to_string());
+    }
+
+    let updated = TRAILS.with_borrow_mut(|trails| {
+        active
+            .into_iter()
+            .filter(|shipment_id| {
+                trails
+                    .entry(*shipment_id)
+                    .or_default()
+                    .record(point.clone())
+            })
+            .collect::<Vec<_>>()
+    });
+
+    for shipment_id in updated {
+        record_event(
+            env,
+            ShipmentEvent::LocationUpdated {
+                shipment_id,
+                carrier: carrier_id,
+                lat,
+                lng,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+pub fn get_tracking(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+) -> Result<Tracking, String> {
+    let caller = env.caller();
+
+    let proof_of_delivery = SHIPMENTS.with_borrow(|shipments| {
+        let shipment = shipments
+            .get(&shipment_id)
+            .ok_or("Shipment not found".to_string())?;
+
+        check_read(caller, shipment, TRACKING_READERS)?;
+
+        Ok::<_, String>(shipment.proof_of_delivery().cloned())
+    })?;
+
+    Ok(TRAILS.with_borrow(|trails| {
+        let trail = trails.get(&shipment_id);
+
+        Tracking {
+            latest: trail.and_then(|trail| trail.latest().cloned()),
+            trail: trail.map(|trail| trail.points()).unwrap_or_default(),
+            proof_of_delivery,
+            processing: processing::get(shipment_id),
+        }
+    }))
+}
+
+pub fn register_customer(env: &impl Environment, profile: Profile) -> Result<(), String> {
+    check_writable()?;
+
+    let customer_id = env.caller();
+    check_anonymous(customer_id)?;
+    profile.validate().map_err(|e| e.to_string())?;
+
+    CUSTOMERS
+        .with_borrow_mut(|customers| customers.register(customer_id, profile))
+        .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::CustomerRegistered {
+            customer: customer_id,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn register_carrier(env: &impl Environment, profile: Profile) -> Result<(), String> {
+    check_writable()?;
+
+    let carrier_id = env.caller();
+    check_anonymous(carrier_id)?;
+    profile.validate().map_err(|e| e.to_string())?;
+
+    CARRIERS
+        .with_borrow_mut(|carriers| carriers.register(carrier_id, profile))
+        .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::CarrierRegistered {
+            carrier: carrier_id,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn update_profile(env: &impl Environment, profile: Profile) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    profile.validate().map_err(|e| e.to_string())?;
+
+    let customer = CUSTOMERS.with_borrow_mut(|customers| {
+        customers
+            .get_mut(&caller)
+            .map(|customer| customer.update_profile(profile.clone()))
+            .is_some()
+    });
+    let carrier = CARRIERS.with_borrow_mut(|carriers| {
+        carriers
+            .get_mut(&caller)
+            .map(|carrier| carrier.update_profile(profile.clone()))
+            .is_some()
+    });
+
+    if !customer && !carrier {
+        return Err("Caller is not registered".to_string());
+    }
+
+    record_event(env, ShipmentEvent::ProfileUpdated { principal: caller });
+
+    Ok(())
+}
+
+pub fn get_my_profile(env: &impl Environment) -> AccountProfiles {
+    let caller = env.caller();
+
+    AccountProfiles {
+        customer: CUSTOMERS.with_borrow(|customers| {
+            customers
+                .get(&caller)
+                .map(|customer| customer.profile().clone())
+        }),
+        carrier: CARRIERS.with_borrow(|carriers| {
+            carriers
+                .get(&caller)
+                .map(|carrier| carrier.profile().clone())
+        }),
+    }
+}
+
+pub fn set_vehicles(env: &impl Environment, vehicles: Vec<Vehicle>) -> Result<(), String> {
+    check_writable()?;
+
+    let carrier_id = env.caller();
+
+    CARRIERS
+        .with_borrow_mut(|carriers| {
+            carriers
+                .get_mut(&carrier_id)
+                .ok_or(anyhow!("Carrier not registered"))?
+                .set_vehicles(vehicles)
+        })
+        .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::VehiclesUpdated {
+            carrier: carrier_id,
+        },
+    );
+
+    Ok(())
+}
+
+/// Checks everything `deposit_collateral` does before the transfer is
+/// requested from the ledger, and returns the ledger to request it from.
+pub fn check_deposit(env: &impl Environment, amount: u64) -> Result<Principal, String> {
+    check_writable()?;
+
+    let carrier_id = env.caller();
+    check_anonymous(carrier_id)?;
+
+    if amount == 0 {
+        return Err("Amount must be positive".to_string());
+    }
+    CARRIERS
+        .with_borrow(|carriers| {
+            carriers
+                .get(&carrier_id)
+                .ok_or(anyhow!("Carrier not registered"))?
+                .check_deposit(amount)
+        })
+        .map_err(|e| e.to_string())?;
+
+    COLLATERAL_CONFIG
+        .with_borrow(|config| config.ledger())
+        .map_err(|e| e.to_string())
+}
+
+/// Credits a deposit once the ledger transferred it. It does not check the
+/// mode again, since the tokens have already been taken.
+pub fn deposit_collateral(env: &impl Environment, amount: u64) -> Result<(), String> {
+    let carrier_id = env.caller();
+
+    CARRIERS
+        .with_borrow_mut(|carriers| {
+            carriers
+                .get_mut(&carrier_id)
+                .ok_or(anyhow!("Carrier not registered"))?
+                .deposit_collateral(amount)
+        })
+        .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::CollateralDeposited {
+            carrier: carrier_id,
+            amount,
+        },
+    );
+
+    Ok(())
+}
+
+/// Takes `amount` out of the free collateral before the ledger sends it, so
+/// that concurrent withdrawals cannot spend it twice. Returns the ledger to
+/// send it from.
+pub fn withdraw_collateral(env: &impl Environment, amount: u64) -> Result<Principal, String> {
+    check_writable()?;
+
+    let carrier_id = env.caller();
+    let ledger = COLLATERAL_CONFIG
+        .with_borrow(|config| config.ledger())
+        .map_err(|e| e.to_string())?;
+
+    CARRIERS
+        .with_borrow_mut(|carriers| {
+            carriers
+                .get_mut(&carrier_id)
+                .ok_or(anyhow!("Carrier not found"))?
+                .withdraw_collateral(amount)
+        })
+        .map_err(|e| e.to_string())?;
+
+    Ok(ledger)
+}
+
+/// Records a withdrawal the ledger sent, or gives the collateral back if it
+/// did not.
+pub fn finish_withdrawal(
+    env: &impl Environment,
+    amount: u64,
+    transfer: Result<(), String>,
+) -> Result<(), String> {
+    let carrier_id = env.caller();
+
+    if let Err(e) = transfer {
+        CARRIERS.with_borrow_mut(|carriers| {
+            if let Some(carrier) = carriers.get_mut(&carrier_id) {
+                // Cannot overflow, the amount was part of the deposit before.
+                let _ = carrier.deposit_collateral(amount);
+            }
+        });
+
+        return Err(e);
+    }
+
+    record_event(
+        env,
+        ShipmentEvent::CollateralWithdrawn {
+            carrier: carrier_id,
+            amount,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn set_collateral_config(
+    env: &impl Environment,
+    config: CollateralConfig,
+) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    COLLATERAL_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn create_shipment(
+    env: &impl Environment,
+    shipment_name: String,
+    hashed_secret: String,
+    shipment_info: ShipmentInfo,
+) -> Result<ShipmentIdInner, String> {
+    check_marketplace_open()?;
+
+    create_one(env, shipment_name, hashed_secret, shipment_info)
+}
+
+pub fn create_shipments(
+    env: &impl Environment,
+    items: Vec<NewShipment>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<ShipmentIdInner, String>>, String> {
+    check_marketplace_open()?;
+
+    run_batch(items, all_or_nothing, |item| {
+        create_one(env, item.name, item.hashed_secret, item.info)
+    })
+}
+
+fn create_one(
+    env: &impl Environment,
+    shipment_name: String,
+    hashed_secret: String,
+    shipment_info: ShipmentInfo,
+) -> Result<ShipmentIdInner, String> {
+    let customer_id = env.caller();
+    check_anonymous(customer_id)?;
+    check_not_banned(customer_id)?;
+
+    Shipment::validate_listing(&shipment_name, &hashed_secret).map_err(|e| e.to_string())?;
+    shipment_info
+        .validate(env.now())
+        .map_err(|e| e.to_string())?;
+
+    PRICING_CONFIG.with_borrow(|config| {
+        if !config.enforce_minimum_price {
+            return Ok(());
+        }
+
+        let quote = config.quote(&shipment_info).map_err(|e| e.to_string())?;
+        if shipment_info.price() < quote.total {
+            return Err(format!("Price is below the minimum of {}", quote.total));
+        }
+
+        Ok(())
+    })?;
+
+    let now = env.now();
+    let pending =
+        SHIPMENTS.with_borrow(|shipments| shipments.count_pending_for_customer(&customer_id));
+    let quotas = QUOTA_CONFIG.with_borrow(|config| config.clone());
+    USAGE
+        .with_borrow_mut(|usage| {
+            let usage = usage.entry(customer_id).or_default();
+            usage.check_create(&quotas, pending, now)
+        })
+        .map_err(|e| e.to_string())?;
+
+    let shipment_id = transaction(|tx| {
+        let customer = tx
+            .customer(customer_id)
+            .ok_or(anyhow!("Customer not registered"))?;
+        let shipment_id = ShipmentId::new(env).into_inner();
+        let shipment = Shipment::create(
+            env,
+            customer,
+            shipment_id,
+            hashed_secret,
+            shipment_name,
+            shipment_info,
+        );
+        tx.insert_shipment(shipment);
+
+        Ok(shipment_id)
+    })
+    .map_err(|e| e.to_string())?;
+
+    USAGE.with_borrow_mut(|usage| usage.entry(customer_id).or_default().record_create(now));
+    record_event(env, ShipmentEvent::Created { shipment_id });
+
+    Ok(shipment_id)
+}
+
+pub fn get_pending_shipments(env: &impl Environment) -> Vec<Shipment> {
+    let caller = env.caller();
+
+    SHIPMENTS
+        .with_borrow(|shipments| shipments.get_all_pending())
+        .iter()
+        .map(|shipment| shipment_view(caller, shipment))
+        .collect()
+}
+
+pub fn get_user_shipments(env: &impl Environment) -> (Vec<Shipment>, Vec<Shipment>) {
+    let customer_id = env.caller();
+
+    let shippers = SHIPMENTS.with_borrow(|shipments| shipments.get_all_for_shipper(&customer_id));
+    let customers = SHIPMENTS.with_borrow(|shipments| shipments.get_all_for_customer(&customer_id));
+    (shippers, customers)
+}
+
+pub fn roles(env: &impl Environment) -> (bool, bool) {
+    let carrier = CARRIERS.with_borrow(|carriers| carriers.contains_key(&env.caller()));
+    let customer = CUSTOMERS.with_borrow(|customers| customers.contains_key(&env.caller()));
+
+    (carrier, customer)
+}
+
+pub fn shipments(env: &impl Environment) -> Result<Vec<Shipment>, String> {
+    check_auditor(env.caller())?;
+
+    Ok(SHIPMENTS.with_borrow(|shipments| shipments.values().cloned().collect()))
+}
+
+pub fn get_shipment(env: &impl Environment, shipment_id: ShipmentIdInner) -> Option<Shipment> {
+    let caller = env.caller();
+
+    SHIPMENTS.with_borrow(|shipments| {
+        shipments
+            .get(&shipment_id)
+            .map(|shipment| shipment_view(caller, shipment))
+    })
+}
+
+pub fn set_sla_config(env: &impl Environment, config: SlaConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    SLA_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn set_expiry_config(env: &impl Environment, config: ExpiryConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    EXPIRY_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn set_mode(env: &impl Environment, mode: OperationalMode) -> Result<(), String> {
+    let caller = env.caller();
+    check_admin(caller)?;
+
+    MODE.set(mode);
+    record_event(
+        env,
+        ShipmentEvent::ModeChanged {
+            mode,
+            changed_by: caller,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn get_metrics(env: &impl Environment) -> Result<Metrics, String> {
+    check_auditor(env.caller())?;
+
+    Ok(Metrics::collect())
+}
+
+pub fn set_quota_config(env: &impl Environment, config: QuotaConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    QUOTA_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn set_geofence_config(env: &impl Environment, config: GeofenceConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    GEOFENCE_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn set_pricing_config(env: &impl Environment, config: PricingConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    PRICING_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn set_fee_config(env: &impl Environment, config: FeeConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    config.validate().map_err(|e| e.to_string())?;
+
+    FEE_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn get_fee_ledger(env: &impl Environment) -> Result<FeeLedger, String> {
+    check_auditor(env.caller())?;
+
+    Ok(FEE_LEDGER.with_borrow(|ledger| ledger.clone()))
+}
+
+/// Takes `amount` out of the available fees before the ledger sends it, so
+/// that concurrent withdrawals cannot spend it twice. Returns the ledger to
+/// send it from.
+pub fn withdraw_fees(env: &impl Environment, amount: u64) -> Result<Principal, String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+    let ledger = COLLATERAL_CONFIG
+        .with_borrow(|config| config.ledger())
+        .map_err(|e| e.to_string())?;
+
+    FEE_LEDGER
+        .with_borrow_mut(|ledger| ledger.withdraw(amount))
+        .map_err(|e| e.to_string())?;
+
+    Ok(ledger)
+}
+
+/// Records a fee withdrawal the ledger sent to `to`, or gives the fees back
+/// if it did not.
+pub fn finish_fee_withdrawal(
+    env: &impl Environment,
+    amount: u64,
+    to: Principal,
+    transfer: Result<(), String>,
+) -> Result<(), String> {
+    if let Err(e) = transfer {
+        FEE_LEDGER.with_borrow_mut(|ledger| ledger.restore(amount));
+
+        return Err(e);
+    }
+
+    record_event(env, ShipmentEvent::FeesWithdrawn { amount, to });
+
+    Ok(())
+}
+
+pub fn set_dispute_config(env: &impl Environment, config: DisputeConfig) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    DISPUTE_CONFIG.with_borrow_mut(|current| *current = config);
+
+    Ok(())
+}
+
+pub fn add_arbiter(env: &impl Environment, arbiter: Principal) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    ARBITERS.with_borrow_mut(|arbiters| arbiters.insert(arbiter));
+
+    Ok(())
+}
+
+pub fn remove_arbiter(env: &impl Environment, arbiter: Principal) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    ARBITERS.with_borrow_mut(|arbiters| arbiters.remove(&arbiter));
+
+    Ok(())
+}
+
+pub fn add_auditor(env: &impl Environment, auditor: Principal) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    AUDITORS.with_borrow_mut(|auditors| auditors.insert(auditor));
+
+    Ok(())
+}
+
+pub fn remove_auditor(env: &impl Environment, auditor: Principal) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    AUDITORS.with_borrow_mut(|auditors| auditors.remove(&auditor));
+
+    Ok(())
+}
+
+pub fn force_shipment_status(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    status: ShipmentStatus,
+    reason: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_admin(caller)?;
+    check_reason(&reason)?;
+
+    let now = env.now();
+    let guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Repair).map_err(|e| e.to_string())?;
+
+    let (from, refund) = transaction(|tx| {
+        if status == ShipmentStatus::Cancelled {
+            release_waiting_legs(tx, shipment_id)?;
+        }
+
+        let shipment = tx
+            .shipment(shipment_id)
+            .ok_or(anyhow!("Shipment not found"))?;
+        let from = shipment.status().clone();
+
+        let refund = match shipment.carrier_id() {
+            Some(_) => {
+                let (shipment, carrier, customer) = tx.parties(shipment_id)?;
+                shipment.force_status(status.clone(), Some(carrier), customer, now)?
+            }
+            None => {
+                let (shipment, customer) = tx.shipment_with_customer(shipment_id)?;
+                shipment.force_status(status.clone(), None, customer, now)?
+            }
+        };
+
+        Ok((from, refund))
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::AdminActionTaken {
+            admin: caller,
+            action: AdminAction::ForceStatus {
+                shipment_id,
+                from,
+                to: status.clone(),
+            },
+            reason,
+        },
+    );
+    record_event(
+        env,
+        ShipmentEvent::StatusUpdated {
+            shipment_id,
+            status: status.clone(),
+        },
+    );
+    if let Some(refund) = refund {
+        jobs::refund_expired(env, shipment_id, refund);
+    }
+
+    drop(guard);
+    if status == ShipmentStatus::Delivered {
+        jobs::settle(env, shipment_id);
+    }
+
+    Ok(())
+}
+
+pub fn reassign_carrier(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    carrier_id: Principal,
+    reason: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_admin(caller)?;
+    check_reason(&reason)?;
+
+    let now = env.now();
+    let _guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Repair).map_err(|e| e.to_string())?;
+
+    let from = transaction(|tx| {
+        let mut to = tx
+            .carrier(carrier_id)
+            .ok_or(anyhow!("Carrier not registered"))?
+            .clone();
+
+        let (shipment, from, _) = tx.parties(shipment_id)?;
+        shipment.reassign_carrier(from, &mut to, now)?;
+        let from = from.id();
+
+        *tx.carrier(carrier_id).ok_or(anyhow!("Carrier not found"))? = to;
+
+        Ok(from)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::AdminActionTaken {
+            admin: caller,
+            action: AdminAction::ReassignCarrier {
+                shipment_id,
+                from,
+                to: carrier_id,
+            },
+            reason,
+        },
+    );
+    record_event(
+        env,
+        ShipmentEvent::CarrierAssigned {
+            shipment_id,
+            carrier: carrier_id,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn remove_shipment(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    reason: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_admin(caller)?;
+    check_reason(&reason)?;
+
+    let _guard =
+        ShipmentGuard::acquire(env, shipment_id, Operation::Repair).map_err(|e| e.to_string())?;
+
+    // The shipment stays as a cancelled record, so its events and refund can
+    // still be traced back to it.
+    let refund = transaction(|tx| {
+        release_waiting_legs(tx, shipment_id)?;
+
+        let (shipment, customer) = tx.shipment_with_customer(shipment_id)?;
+        if *shipment.status() != ShipmentStatus::Pending {
+            return Err(anyhow!("Only pending shipments can be removed"));
+        }
+
+        shipment.expire(customer)
+    })
+    .map_err(|e| e.to_string())?;
+
+    record_event(
+        env,
+        ShipmentEvent::AdminActionTaken {
+            admin: caller,
+            action: AdminAction::RemoveListing { shipment_id },
+            reason,
+        },
+    );
+    jobs::refund_expired(env, shipment_id, refund);
+
+    Ok(())
+}
+
+/// Frees carriers holding later legs of a shipment that will not reach them.
+fn release_waiting_legs(
+    tx: &mut state::Transaction,
+    shipment_id: ShipmentIdInner,
+) -> anyhow::Result<()> {
+    let waiting = tx
+        .shipment(shipment_id)
+        .ok_or(anyhow!("Shipment not found"))?
+        .waiting_carriers();
+
+    for carrier_id in waiting {
+        let (shipment, carrier) = tx.shipment_with_carrier(shipment_id, carrier_id)?;
+        shipment.release_leg(carrier)?;
+    }
+
+    Ok(())
+}
+
+pub fn get_banned_principals(env: &impl Environment) -> Result<Vec<Principal>, String> {
+    check_auditor(env.caller())?;
+
+    Ok(BANNED.with_borrow(|banned| banned.iter().copied().collect()))
+}
+
+pub fn ban_principal(
+    env: &impl Environment,
+    principal: Principal,
+    reason: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_admin(caller)?;
+    check_reason(&reason)?;
+
+    if !BANNED.with_borrow_mut(|banned| banned.insert(principal)) {
+        return Err("Principal is already banned".to_string());
+    }
+
+    record_event(
+        env,
+        ShipmentEvent::AdminActionTaken {
+            admin: caller,
+            action: AdminAction::Ban { principal },
+            reason,
+        },
+    );
+
+    Ok(())
+}
+
+pub fn unban_principal(
+    env: &impl Environment,
+    principal: Principal,
+    reason: String,
+) -> Result<(), String> {
+    check_writable()?;
+
+    let caller = env.caller();
+    check_admin(caller)?;
+    check_reason(&reason)?;
+
+    if !BANNED.with_borrow_mut(|banned| banned.remove(&principal)) {
+        return Err("Principal is not banned".to_string());
+    }
+
+    record_event(
+        env,
+        ShipmentEvent::AdminActionTaken {
+            admin: caller,
+            action: AdminAction::Unban { principal },
+            reason,
+        },
+    );
+
+    Ok(())
+}
+
+/// Runs `f` for each item of a batch and collects the results in order. With
+/// `all_or_nothing` the first failure traps, so the whole call is rolled back.
+fn run_batch<I, T>(
+    items: Vec<I>,
+    all_or_nothing: bool,
+    mut f: impl FnMut(I) -> Result<T, String>,
+) -> Result<Vec<Result<T, String>>, String> {
+    if items.len() > MAX_BATCH_SIZE {
+        return Err(format!("Batch cannot exceed {MAX_BATCH_SIZE} items"));
+    }
+
+    Ok(items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let result = f(item);
+            if let (true, Err(e)) = (all_or_nothing, &result) {
+                ic_cdk::trap(&format!("Batch item {index} failed: {e}"));
+            }
+
+            result
+        })
+        .collect())
+}
+
+/// Admins and auditors get every event. Everyone else only gets the events of
+/// shipments they may track and of their own account.
+pub fn get_events(env: &impl Environment, since_sequence: Option<u64>) -> Vec<TimestampedEvent> {
+    let caller = env.caller();
+    let sees_all = check_auditor(caller).is_ok();
+
+    let visible = |event: &ShipmentEvent| {
+        if sees_all {
+            return true;
+        }
+        if let Some(shipment_id) = event.shipment_id() {
+            return SHIPMENTS.with_borrow(|shipments| {
+                shipments
+                    .get(&shipment_id)
+                    .is_some_and(|shipment| check_read(caller, shipment, TRACKING_READERS).is_ok())
+            });
+        }
+
+        event.account() == Some(caller)
+    };
+
+    EVENTS.with_borrow(|events| {
+        events
+            .iter()
+            .filter(|e| since_sequence.is_none_or(|seq| e.sequence > seq))
+            .filter(|e| visible(&e.event))
+            .cloned()
+            .collect()
+    })
+}
+
+pub fn purge_old_events(env: &impl Environment) -> Result<(), String> {
+    check_writable()?;
+
+    check_admin(env.caller())?;
+
+    let current_time = env.now();
+    let current_time_secs = current_time / 1_000_000_000;
+
+    EVENTS.with(|events| {
+        events
+            .borrow_mut()
+            .retain(|e| current_time_secs - e.timestamp < MAX_EVENTS_AGE);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests;
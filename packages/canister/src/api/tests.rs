@@ -0,0 +1,383 @@
+//! Endpoint flows run against the thread-local state, one canister per test.
+
+use super::*;
+use crate::{
+    env::TestEnv,
+    test_utils::{principal, shipment_info, HASH, SECOND, SECRET},
+    ShipmentEvent,
+};
+
+/// Registers `customer` and `carrier`, funds the carrier's collateral and lists
+/// a shipment, leaving the customer as the caller.
+fn setup(env: &TestEnv, customer: Principal, carrier: Principal) -> ShipmentIdInner {
+    env.set_caller(carrier);
+    register_carrier(env, Profile::new("Carrier".to_string())).unwrap();
+    deposit_collateral(env, 1_000).unwrap();
+
+    env.set_caller(customer);
+    register_customer(env, Profile::new("Customer".to_string())).unwrap();
+
+    create_shipment(env, "name".to_string(), HASH.to_string(), shipment_info()).unwrap()
+}
+
+fn status(env: &TestEnv, shipment_id: ShipmentIdInner) -> ShipmentStatus {
+    get_shipment(env, shipment_id).unwrap().status().clone()
+}
+
+#[test]
+fn test_direct_shipment_flow() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier) = (principal(1), principal(2));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+    assert_eq!(
+        buy_shipment(&env, shipment_id).unwrap_err(),
+        "shipment is not pending"
+    );
+
+    env.advance(SECOND);
+    pickup_shipment(&env, shipment_id, None).unwrap();
+    assert_eq!(status(&env, shipment_id), ShipmentStatus::InTransit);
+
+    env.advance(SECOND);
+    finalize_shipment(&env, shipment_id, Some(SECRET.to_string()), None).unwrap();
+    assert_eq!(status(&env, shipment_id), ShipmentStatus::Delivered);
+
+    let events = get_events(&env, None);
+    assert!(events.iter().any(|event| matches!(
+        event.event,
+        ShipmentEvent::CarrierAssigned { carrier: assigned, .. } if assigned == carrier
+    )));
+    assert!(events
+        .iter()
+        .any(|event| matches!(event.event, ShipmentEvent::Finalized { shipment_id: id } if id == shipment_id)));
+}
+
+#[test]
+fn test_pickup_is_checked_and_guarded() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier) = (principal(1), principal(2));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+
+    assert_eq!(
+        pickup_shipment(&TestEnv::new(SECOND), shipment_id, None).unwrap_err(),
+        "Cannot be called anonymously"
+    );
+
+    let guard = ShipmentGuard::acquire(&env, shipment_id, Operation::Finalize).unwrap();
+    assert_eq!(
+        pickup_shipment(&env, shipment_id, None).unwrap_err(),
+        "Shipment is already being processed"
+    );
+    drop(guard);
+
+    pickup_shipment(&env, shipment_id, None).unwrap();
+    assert_eq!(status(&env, shipment_id), ShipmentStatus::InTransit);
+}
+
+#[test]
+fn test_endpoints_require_registration() {
+    let env = TestEnv::new(SECOND);
+    env.set_caller(principal(1));
+
+    assert_eq!(
+        create_shipment(&env, "name".to_string(), HASH.to_string(), shipment_info()).unwrap_err(),
+        "Customer not registered"
+    );
+    assert_eq!(
+        create_shipment(
+            &TestEnv::new(SECOND),
+            "name".to_string(),
+            HASH.to_string(),
+            shipment_info()
+        )
+        .unwrap_err(),
+        "Cannot be called anonymously"
+    );
+}
+
+#[test]
+fn test_all_or_nothing_finalize_counts_wrong_secrets() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier) = (principal(1), principal(2));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+
+    let guesses = vec![(shipment_id, Some("wrong".to_string()), None); 10];
+    assert_eq!(
+        finalize_shipments(&env, guesses.clone(), true).unwrap_err(),
+        "Batch item 0 failed: secret verification failed"
+    );
+
+    let limit = QuotaConfig::default().failed_finalizes_per_hour.unwrap();
+    for _ in 1..limit {
+        assert!(finalize_shipments(&env, guesses.clone(), true).is_err());
+    }
+
+    assert_eq!(
+        finalize_shipments(&env, guesses, true).unwrap_err(),
+        "Batch item 0 failed: Too many failed finalize attempts, try again later"
+    );
+    assert_eq!(
+        finalize_shipment(&env, shipment_id, Some(SECRET.to_string()), None).unwrap_err(),
+        "Too many failed finalize attempts, try again later"
+    );
+    assert_eq!(status(&env, shipment_id), ShipmentStatus::Bought);
+}
+
+#[test]
+fn test_jobs_settle_after_the_dispute_window_and_drop_the_trail() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier) = (principal(1), principal(2));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+    pickup_shipment(&env, shipment_id, None).unwrap();
+    report_location(&env, 51.0, 20.0, 10.0).unwrap();
+    finalize_shipment(&env, shipment_id, Some(SECRET.to_string()), None).unwrap();
+
+    let settlements = || {
+        get_events(&env, None)
+            .iter()
+            .filter(|event| matches!(event.event, ShipmentEvent::Settled { .. }))
+            .count()
+    };
+
+    let has_trail = || TRAILS.with_borrow(|trails| trails.contains_key(&shipment_id));
+
+    jobs::run(&env);
+    assert_eq!(settlements(), 0);
+    assert!(has_trail());
+
+    env.advance(DisputeConfig::default().window());
+    jobs::run(&env);
+    jobs::run(&env);
+    assert_eq!(settlements(), 1);
+    assert!(!has_trail());
+}
+
+#[test]
+fn test_batch_finalize_checks_each_proof() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier) = (principal(1), principal(2));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+    pickup_shipment(&env, shipment_id, None).unwrap();
+
+    let proof = |lat: f64| ProofOfDelivery {
+        photo_hash: None,
+        signature_hash: None,
+        position: Some(LocationPoint {
+            lat,
+            lng: 19.945,
+            accuracy_m: 10.0,
+            reported_at: env.now(),
+        }),
+        captured_at: env.now(),
+    };
+    let secret = Some(SECRET.to_string());
+
+    let results = finalize_shipments(
+        &env,
+        vec![(shipment_id, secret.clone(), Some(proof(52.0)))],
+        false,
+    )
+    .unwrap();
+    assert_eq!(
+        results,
+        vec![Err(
+            "delivery position is too far from the destination".to_string()
+        )]
+    );
+    assert_eq!(status(&env, shipment_id), ShipmentStatus::InTransit);
+
+    // Only wrong secrets use up the quota, a bad position fix does not.
+    let limit = QuotaConfig::default().failed_finalizes_per_hour.unwrap();
+    for _ in 0..limit {
+        let far = Some(proof(52.0));
+        assert!(finalize_shipment(&env, shipment_id, secret.clone(), far).is_err());
+    }
+
+    let results = finalize_shipments(
+        &env,
+        vec![(shipment_id, secret, Some(proof(50.0647)))],
+        false,
+    )
+    .unwrap();
+    assert_eq!(results, vec![Ok(())]);
+    assert!(get_shipment(&env, shipment_id)
+        .unwrap()
+        .proof_of_delivery()
+        .is_some());
+}
+
+#[test]
+fn test_events_are_visible_to_parties_and_auditors() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier, stranger, auditor) =
+        (principal(1), principal(2), principal(3), principal(5));
+    let shipment_id = setup(&env, customer, carrier);
+    AUDITORS.with_borrow_mut(|auditors| auditors.insert(auditor));
+
+    env.set_caller(carrier);
+    buy_shipment(&env, shipment_id).unwrap();
+    pickup_shipment(&env, shipment_id, None).unwrap();
+    report_location(&env, 51.0, 20.0, 10.0).unwrap();
+
+    let is_location = |event: &TimestampedEvent| matches!(event.event, ShipmentEvent::LocationUpdated { shipment_id: id, .. } if id == shipment_id);
+
+    for party in [customer, carrier, auditor] {
+        env.set_caller(party);
+        assert!(get_events(&env, None).iter().any(is_location));
+    }
+
+    env.set_caller(stranger);
+    assert!(get_events(&env, None).is_empty());
+
+    env.set_caller(Principal::anonymous());
+    assert!(get_events(&env, None).is_empty());
+}
+
+#[test]
+fn test_recipient_reads_and_tracks_the_shipment() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier, recipient) = (principal(1), principal(2), principal(3));
+    let shipment_id = setup(&env, customer, carrier);
+
+    env.set_caller(recipient);
+    assert_eq!(
+        get_tracking(&env, shipment_id).err().unwrap(),
+        "Caller is not a party of the shipment"
+    );
+    assert_eq!(
+        set_recipient(&env, shipment_id, Some(recipient)).unwrap_err(),
+        "only the customer can set the recipient"
+    );
+
+    env.set_caller(customer);
+    set_recipient(&env, shipment_id, Some(recipient)).unwrap();
+
+    env.set_caller(recipient);
+    assert!(get_tracking(&env, shipment_id).is_ok());
+    assert_eq!(
+        get_shipment(&env, shipment_id).unwrap().recipient_id(),
+        Some(recipient)
+    );
+    assert_eq!(
+        get_dispute_case(&env, shipment_id).err().unwrap(),
+        "Cannot be called by non-arbiters"
+    );
+}
+
+#[test]
+fn test_admin_cancellations_refund_the_customer() {
+    let env = TestEnv::new(SECOND);
+    let (customer, carrier, admin) = (principal(1), principal(2), principal(3));
+    let forced = setup(&env, customer, carrier);
+    let removed =
+        create_shipment(&env, "name".to_string(), HASH.to_string(), shipment_info()).unwrap();
+    ADMINS.with_borrow_mut(|admins| admins.insert(admin));
+
+    env.set_caller(carrier);
+    buy_shipment(&env, forced).unwrap();
+
+    env.set_caller(admin);
+    force_shipment_status(&env, forced, ShipmentStatus::Cancelled, "lost".to_string()).unwrap();
+    remove_shipment(&env, removed, "abusive listing".to_string()).unwrap();
+
+    let refunds = get_events(&env, None)
+        .into_iter()
+        .filter_map(|event| match event.event {
+            ShipmentEvent::Expired {
+                shipment_id,
+                refund,
+            } => Some((shipment_id, refund)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(refunds, vec![(forced, 1_000), (removed, 1_000)]);
+    assert_eq!(status(&env, forced), ShipmentStatus::Cancelled);
+    assert_eq!(status(&env, removed), ShipmentStatus::Cancelled);
+}
+
+#[test]
+fn test_collateral_moves_through_the_ledger() {
+    let env = TestEnv::new(SECOND);
+    let carrier = principal(2);
+    env.set_caller(carrier);
+    register_carrier(&env, Profile::new("Carrier".to_string())).unwrap();
+
+    assert_eq!(
+        check_deposit(&env, 1_000).unwrap_err(),
+        "collateral ledger is not configured"
+    );
+
+    let ledger = principal(9);
+    COLLATERAL_CONFIG.with_borrow_mut(|config| config.ledger = Some(ledger));
+    assert_eq!(check_deposit(&env, 1_000), Ok(ledger));
+    deposit_collateral(&env, 1_000).unwrap();
+    assert_eq!(
+        check_deposit(&env, u64::MAX).unwrap_err(),
+        "collateral overflow"
+    );
+
+    assert_eq!(withdraw_collateral(&env, 600), Ok(ledger));
+    assert_eq!(
+        withdraw_collateral(&env, 600).unwrap_err(),
+        "not enough free collateral"
+    );
+
+    // A failed transfer gives the collateral back.
+    assert!(finish_withdrawal(&env, 600, Err("rejected".to_string())).is_err());
+    assert_eq!(withdraw_collateral(&env, 1_000), Ok(ledger));
+    finish_withdrawal(&env, 1_000, Ok(())).unwrap();
+
+    let free = CARRIERS.with_borrow(|carriers| carriers.get(&carrier).unwrap().free_collateral());
+    assert_eq!(free, 0);
+}
+
+#[test]
+fn test_fee_withdrawals_go_through_the_ledger() {
+    let env = TestEnv::new(SECOND);
+    let (admin, treasury) = (principal(3), principal(4));
+    ADMINS.with_borrow_mut(|admins| admins.insert(admin));
+    FEE_LEDGER.with_borrow_mut(|ledger| ledger.accrue(1_000));
+
+    env.set_caller(admin);
+    assert_eq!(
+        withdraw_fees(&env, 600).unwrap_err(),
+        "collateral ledger is not configured"
+    );
+
+    let ledger = principal(9);
+    COLLATERAL_CONFIG.with_borrow_mut(|config| config.ledger = Some(ledger));
+    assert_eq!(withdraw_fees(&env, 600), Ok(ledger));
+    assert_eq!(
+        withdraw_fees(&env, 600).unwrap_err(),
+        "not enough fees available"
+    );
+
+    // A failed transfer gives the fees back.
+    assert!(finish_fee_withdrawal(&env, 600, treasury, Err("rejected".to_string())).is_err());
+    assert_eq!(withdraw_fees(&env, 1_000), Ok(ledger));
+    finish_fee_withdrawal(&env, 1_000, treasury, Ok(())).unwrap();
+
+    assert_eq!(FEE_LEDGER.with_borrow(|ledger| ledger.available()), 0);
+    let withdrawn = EVENTS.with_borrow(|events| {
+        events
+            .iter()
+            .filter(|event| matches!(event.event, ShipmentEvent::FeesWithdrawn { .. }))
+            .count()
+    });
+    assert_eq!(withdrawn, 1);
+}
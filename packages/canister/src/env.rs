@@ -0,0 +1,82 @@
+use crate::{models::shipment_id::ShipmentIdInner, state::SHIPMENT_COUNTER};
+use candid::Principal;
+
+/// Everything the models need from the outside world, so that they can run
+/// under plain `cargo test` where the system API is not available.
+pub trait Environment {
+    /// Nanoseconds since the epoch.
+    fn now(&self) -> u64;
+    fn caller(&self) -> Principal;
+    fn next_shipment_id(&self) -> ShipmentIdInner;
+}
+
+/// The environment of a running canister.
+pub struct CanisterEnv;
+
+impl Environment for CanisterEnv {
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn caller(&self) -> Principal {
+        ic_cdk::caller()
+    }
+
+    fn next_shipment_id(&self) -> ShipmentIdInner {
+        SHIPMENT_COUNTER.with_borrow_mut(|counter| {
+            let current = *counter;
+            *counter = current + 1;
+            current
+        })
+    }
+}
+
+#[cfg(test)]
+pub use test_env::TestEnv;
+
+#[cfg(test)]
+mod test_env {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Environment with a manually driven clock and caller.
+    pub struct TestEnv {
+        now: Cell<u64>,
+        caller: Cell<Principal>,
+        next_id: Cell<ShipmentIdInner>,
+    }
+
+    impl TestEnv {
+        pub fn new(now: u64) -> Self {
+            Self {
+                now: Cell::new(now),
+                caller: Cell::new(Principal::anonymous()),
+                next_id: Cell::new(0),
+            }
+        }
+
+        pub fn advance(&self, nanos: u64) {
+            self.now.set(self.now.get() + nanos);
+        }
+
+        pub fn set_caller(&self, caller: Principal) {
+            self.caller.set(caller);
+        }
+    }
+
+    impl Environment for TestEnv {
+        fn now(&self) -> u64 {
+            self.now.get()
+        }
+
+        fn caller(&self) -> Principal {
+            self.caller.get()
+        }
+
+        fn next_shipment_id(&self) -> ShipmentIdInner {
+            let id = self.next_id.get();
+            self.next_id.set(id + 1);
+            id
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{api, env::CanisterEnv};
+use candid::{CandidType, Deserialize};
+use ic_cdk::query;
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// `/metrics` is restricted to admins and auditors like `getMetrics`. Requests
+/// through the HTTP gateway are anonymous, so scrapers call `http_request`
+/// directly with their identity.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let path = request.url.split('?').next().unwrap_or_default();
+
+    match (request.method.as_str(), path) {
+        ("GET", "/metrics") => match api::get_metrics(&CanisterEnv) {
+            Ok(metrics) => HttpResponse {
+                status_code: 200,
+                headers: vec![(
+                    "Content-Type".to_string(),
+                    "text/plain; version=0.0.4".to_string(),
+                )],
+                body: metrics.to_prometheus().into_bytes(),
+            },
+            Err(e) => HttpResponse {
+                status_code: 403,
+                headers: vec![],
+                body: e.into_bytes(),
+            },
+        },
+        _ => HttpResponse {
+            status_code: 404,
+            headers: vec![],
+            body: b"Not found".to_vec(),
+        },
+    }
+}
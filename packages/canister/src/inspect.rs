@@ -0,0 +1,59 @@
+use crate::{
+    models::shipment::{Shipment, ShipmentInfo},
+    NewShipment,
+};
+use candid::{decode_args, Principal};
+use ic_cdk::{
+    api::call::{accept_message, arg_data_raw, arg_data_raw_size, method_name},
+    inspect_message,
+};
+
+/// Largest argument accepted for a single call, in bytes.
+const MAX_ARG_SIZE: usize = 16 * 1024;
+/// Largest argument accepted for the batch endpoints, in bytes.
+const MAX_BATCH_ARG_SIZE: usize = 256 * 1024;
+const BATCH_METHODS: &[&str] = &["createShipments", "buyShipments", "finalizeShipments"];
+
+/// Drops ingress messages that would fail anyway before the canister pays for
+/// executing them. Only update calls are inspected and calls from other
+/// canisters bypass it, so every endpoint still validates its input.
+#[inspect_message]
+fn inspect_message() {
+    match inspect(&method_name()) {
+        Ok(()) => accept_message(),
+        Err(reason) => ic_cdk::trap(&reason),
+    }
+}
+
+fn inspect(method: &str) -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("Cannot be called anonymously".to_string());
+    }
+
+    let limit = match BATCH_METHODS.contains(&method) {
+        true => MAX_BATCH_ARG_SIZE,
+        false => MAX_ARG_SIZE,
+    };
+    if arg_data_raw_size() > limit {
+        return Err(format!("Arguments of {method} exceed {limit} bytes"));
+    }
+
+    match method {
+        "createShipment" => {
+            let (name, hashed_secret, _): (String, String, ShipmentInfo) =
+                decode_args(&arg_data_raw()).map_err(|e| e.to_string())?;
+
+            Shipment::validate_listing(&name, &hashed_secret).map_err(|e| e.to_string())
+        }
+        "createShipments" => {
+            let (items, _): (Vec<NewShipment>, bool) =
+                decode_args(&arg_data_raw()).map_err(|e| e.to_string())?;
+
+            items.iter().try_for_each(|item| {
+                Shipment::validate_listing(&item.name, &item.hashed_secret)
+                    .map_err(|e| e.to_string())
+            })
+        }
+        _ => Ok(()),
+    }
+}
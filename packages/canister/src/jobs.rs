@@ -0,0 +1,245 @@
+use crate::{
+    env::{CanisterEnv, Environment},
+    models::{shipment::ShipmentStatus, shipment_id::ShipmentIdInner},
+    processing::{self, Operation, ShipmentGuard},
+    record_event, record_settlement,
+    state::{
+        transaction, DISPUTE_CONFIG, EXPIRY_CONFIG, FEE_CONFIG, LAST_JOBS_RUN, MODE, SHIPMENTS,
+        SLA_CONFIG, TRAILS, USAGE,
+    },
+    ShipmentEvent,
+};
+use std::time::Duration;
+
+const JOBS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Timers are not persisted, so this has to run on every install.
+pub fn start() {
+    ic_cdk_timers::set_timer_interval(JOBS_INTERVAL, || run(&CanisterEnv));
+}
+
+pub fn run(env: &impl Environment) {
+    if MODE.with_borrow(|mode| mode.check_write()).is_err() {
+        return;
+    }
+
+    let now = env.now();
+    LAST_JOBS_RUN.set(Some(now));
+
+    recover_stuck(env);
+    prune_usage(now);
+    prune_trails();
+
+    let active = SHIPMENTS.with_borrow(|shipments| shipments.get_all_active_ids());
+    for shipment_id in active {
+        enforce_sla(env, shipment_id);
+    }
+
+    release_overdue_pickups(env);
+    expire_pending(env);
+
+    settle_delivered(env);
+}
+
+/// Settles delivered shipments whose dispute window has passed, skipping
+/// those already paid out.
+fn settle_delivered(env: &impl Environment) {
+    let now = env.now();
+
+    let window = DISPUTE_CONFIG.with_borrow(|config| config.window());
+    let settleable = SHIPMENTS.with_borrow(|shipments| {
+        shipments
+            .values()
+            .filter(|shipment| shipment.is_settleable(window, now))
+            .map(|shipment| shipment.id())
+            .collect::<Vec<_>>()
+    });
+
+    for shipment_id in settleable {
+        settle(env, shipment_id);
+    }
+}
+
+/// Releases shipments whose operation never completed. Nothing of it was
+/// committed, so a stuck settlement is simply retried by `settle`.
+fn recover_stuck(env: &impl Environment) {
+    let now = env.now();
+
+    for (shipment_id, processing) in processing::release_stuck(now) {
+        record_event(
+            env,
+            ShipmentEvent::ProcessingRecovered {
+                shipment_id,
+                operation: processing.operation,
+                started_at: processing.started_at,
+            },
+        );
+    }
+}
+
+/// Forgets principals without activity in the quota window.
+fn prune_usage(now: u64) {
+    USAGE.with_borrow_mut(|usage| usage.retain(|_, usage| usage.prune(now)));
+}
+
+/// Drops the trails of finished shipments. Until then they serve tracking
+/// and dispute cases.
+fn prune_trails() {
+    TRAILS.with_borrow_mut(|trails| {
+        SHIPMENTS.with_borrow(|shipments| {
+            trails.retain(|shipment_id, _| {
+                shipments
+                    .get(shipment_id)
+                    .is_some_and(|shipment| !shipment.is_finished())
+            })
+        })
+    });
+}
+
+/// Settles a delivered shipment if its dispute window has passed.
+pub fn settle(env: &impl Environment, shipment_id: ShipmentIdInner) {
+    let now = env.now();
+
+    let Ok(_guard) = ShipmentGuard::acquire(env, shipment_id, Operation::Settle) else {
+        return;
+    };
+
+    let window = DISPUTE_CONFIG.with_borrow(|config| config.window());
+    let fees = FEE_CONFIG.with_borrow(|config| config.clone());
+
+    let settlement = transaction(|tx| {
+        let (shipment, carrier, _) = tx.parties(shipment_id)?;
+        if !shipment.is_settleable(window, now) {
+            return Ok(None);
+        }
+
+        shipment.settle(carrier, &fees).map(Some)
+    });
+
+    if let Ok(Some(settlement)) = settlement {
+        record_settlement(env, shipment_id, None, settlement);
+    }
+}
+
+fn expire_pending(env: &impl Environment) {
+    let now = env.now();
+
+    let ttl = EXPIRY_CONFIG.with_borrow(|config| config.pending_ttl());
+
+    let expired = SHIPMENTS.with_borrow(|shipments| {
+        shipments
+            .values()
+            .filter(|shipment| shipment.is_expired(ttl, now))
+            .map(|shipment| shipment.id())
+            .collect::<Vec<_>>()
+    });
+
+    for shipment_id in expired {
+        if processing::is_processing(shipment_id) {
+            continue;
+        }
+
+        let refund = transaction(|tx| {
+            let (shipment, customer) = tx.shipment_with_customer(shipment_id)?;
+            let refund = shipment.expire(customer)?;
+
+            for carrier_id in shipment.waiting_carriers() {
+                let (shipment, carrier) = tx.shipment_with_carrier(shipment_id, carrier_id)?;
+                shipment.release_leg(carrier)?;
+            }
+
+            Ok(refund)
+        });
+
+        if let Ok(refund) = refund {
+            refund_expired(env, shipment_id, refund);
+        }
+    }
+}
+
+/// Pays the customer of a cancelled shipment back what was left in escrow.
+pub fn refund_expired(env: &impl Environment, shipment_id: ShipmentIdInner, refund: u64) {
+    record_event(
+        env,
+        ShipmentEvent::Expired {
+            shipment_id,
+            refund,
+        },
+    );
+}
+
+fn release_overdue_pickups(env: &impl Environment) {
+    let now = env.now();
+
+    let config = EXPIRY_CONFIG.with_borrow(|config| config.clone());
+    let timeout = config.pickup_timeout();
+
+    let bought =
+        SHIPMENTS.with_borrow(|shipments| shipments.get_ids_with_status(ShipmentStatus::Bought));
+
+    for shipment_id in bought {
+        if processing::is_processing(shipment_id) {
+            continue;
+        }
+
+        let event = transaction(|tx| {
+            let (shipment, carrier, _) = tx.parties(shipment_id)?;
+            if !shipment.is_pickup_overdue(timeout, now) {
+                return Ok(None);
+            }
+
+            let penalty = config.abandonment_penalty(shipment.info().value());
+            let penalty = shipment.revert_to_pending(carrier, penalty, now)?;
+
+            Ok(Some(ShipmentEvent::PickupTimedOut {
+                shipment_id,
+                carrier: carrier.id(),
+                penalty,
+            }))
+        });
+
+        if let Ok(Some(event)) = event {
+            record_event(env, event);
+        }
+    }
+}
+
+/// Flags missed time windows of a shipment, charges the configured penalties
+/// to its carrier and emits an `SlaBreached` event per new breach.
+pub fn enforce_sla(env: &impl Environment, shipment_id: ShipmentIdInner) {
+    let now = env.now();
+
+    if processing::is_processing(shipment_id) {
+        return;
+    }
+
+    let config = SLA_CONFIG.with_borrow(|config| config.clone());
+
+    let breaches = transaction(|tx| {
+        let (shipment, carrier, _) = tx.parties(shipment_id)?;
+        let carrier_id = carrier.id();
+
+        let breaches = shipment
+            .check_sla(now)
+            .into_iter()
+            .map(|breach| {
+                let penalty = config.penalty(breach, shipment.info().value());
+                let penalty = shipment.apply_penalty(carrier, penalty)?;
+
+                Ok(ShipmentEvent::SlaBreached {
+                    shipment_id,
+                    carrier: carrier_id,
+                    breach,
+                    penalty,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(breaches)
+    })
+    .unwrap_or_default();
+
+    for event in breaches {
+        record_event(env, event);
+    }
+}
@@ -0,0 +1,117 @@
+//! Calls to the ICRC ledger that holds carrier collateral.
+
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Moves `amount` from `from` to the canister, within the allowance `from`
+/// approved. The ledger fee is charged to `from` on top.
+pub async fn transfer_from(ledger: Principal, from: Principal, amount: u64) -> Result<(), String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: from.into(),
+        to: ic_cdk::id().into(),
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, message)| format!("Ledger call failed: {code:?} {message}"))?;
+
+    result
+        .map(|_| ())
+        .map_err(|e| format!("Ledger rejected the transfer: {e:?}"))
+}
+
+/// Sends `amount` from the canister to `to`. The ledger fee comes out of the
+/// amount, so the canister never pays for withdrawals.
+pub async fn transfer(ledger: Principal, to: Principal, amount: u64) -> Result<(), String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {code:?} {message}"))?;
+
+    if amount <= fee {
+        return Err("Amount does not cover the ledger fee".to_string());
+    }
+
+    let args = TransferArg {
+        from_subaccount: None,
+        to: to.into(),
+        amount: Nat::from(amount) - fee.clone(),
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed: {code:?} {message}"))?;
+
+    result
+        .map(|_| ())
+        .map_err(|e| format!("Ledger rejected the transfer: {e:?}"))
+}
@@ -1,16 +1,50 @@
+mod api;
+mod env;
+mod http;
+mod inspect;
+mod jobs;
+mod ledger;
+mod metrics;
 mod models;
+mod processing;
 mod state;
+mod upgrade;
+
+#[cfg(test)]
+mod test_utils;
 
-use anyhow::anyhow;
 use candid::Principal;
 use candid::{CandidType, Deserialize};
+use env::{CanisterEnv, Environment};
+use http::{HttpRequest, HttpResponse};
 use ic_cdk::{init, query, update};
+use metrics::Metrics;
 use models::{
+    collateral::CollateralConfig,
     customer::Customer,
-    shipment::{Shipment, ShipmentInfo, ShipmentLocation, ShipmentStatus, SizeCategory},
+    dispute::{Dispute, DisputeConfig, DisputeOutcome},
+    expiry::ExpiryConfig,
+    fee::{FeeConfig, FeeLedger},
+    geofence::GeofenceConfig,
+    mode::OperationalMode,
+    pricing::{PricingConfig, Quote},
+    profile::Profile,
+    proof::ProofOfDelivery,
+    quota::QuotaConfig,
+    reputation::ReputationSummary,
+    settlement::Settlement,
+    shipment::{Shipment, ShipmentInfo, ShipmentListing, ShipmentLocation, ShipmentStatus},
     shipment_id::{ShipmentId, ShipmentIdInner},
+    size::SizeCategory,
+    sla::{SlaBreach, SlaConfig},
+    tracking::LocationPoint,
+    vehicle::{CargoLoad, Vehicle},
+};
+use processing::{CollateralGuard, Operation, Processing};
+use state::{
+    CARRIERS, COLLATERAL_CONFIG, CUSTOMERS, DISPUTE_CONFIG, EXPIRY_CONFIG, FEE_CONFIG, FEE_LEDGER,
+    GEOFENCE_CONFIG, MODE, PRICING_CONFIG, QUOTA_CONFIG, SHIPMENTS, SLA_CONFIG,
 };
-use state::{CARRIERS, CUSTOMERS, SHIPMENTS};
 use std::collections::HashSet;
 use std::{cell::RefCell, collections::VecDeque};
 
@@ -30,6 +64,207 @@ pub enum ShipmentEvent {
     Finalized {
         shipment_id: ShipmentIdInner,
     },
+    SlaBreached {
+        shipment_id: ShipmentIdInner,
+        carrier: Principal,
+        breach: SlaBreach,
+        penalty: u64,
+    },
+    PickupTimedOut {
+        shipment_id: ShipmentIdInner,
+        carrier: Principal,
+        penalty: u64,
+    },
+    Expired {
+        shipment_id: ShipmentIdInner,
+        refund: u64,
+    },
+    Settled {
+        shipment_id: ShipmentIdInner,
+        /// Set when a single leg was paid out at its handoff.
+        leg: Option<u32>,
+        settlement: Settlement,
+    },
+    DisputeOpened {
+        shipment_id: ShipmentIdInner,
+        opened_by: Principal,
+        reason: String,
+        evidence_hash: String,
+    },
+    DisputeResolved {
+        shipment_id: ShipmentIdInner,
+        arbiter: Principal,
+        outcome: DisputeOutcome,
+    },
+    Rated {
+        shipment_id: ShipmentIdInner,
+        rated_by: Principal,
+        rated: Principal,
+        score: u8,
+    },
+    CollateralDeposited {
+        carrier: Principal,
+        amount: u64,
+    },
+    CollateralWithdrawn {
+        carrier: Principal,
+        amount: u64,
+    },
+    CustomerRegistered {
+        customer: Principal,
+    },
+    CarrierRegistered {
+        carrier: Principal,
+    },
+    ProfileUpdated {
+        principal: Principal,
+    },
+    VehiclesUpdated {
+        carrier: Principal,
+    },
+    FeeCharged {
+        shipment_id: ShipmentIdInner,
+        amount: u64,
+    },
+    FeesWithdrawn {
+        amount: u64,
+        to: Principal,
+    },
+    LegAssigned {
+        shipment_id: ShipmentIdInner,
+        leg: u32,
+        carrier: Principal,
+    },
+    HandoffConfirmed {
+        shipment_id: ShipmentIdInner,
+        leg: u32,
+        from: Principal,
+        to: Principal,
+    },
+    LocationUpdated {
+        shipment_id: ShipmentIdInner,
+        carrier: Principal,
+        lat: f64,
+        lng: f64,
+    },
+    ModeChanged {
+        mode: OperationalMode,
+        changed_by: Principal,
+    },
+    ProcessingRecovered {
+        shipment_id: ShipmentIdInner,
+        operation: Operation,
+        started_at: u64,
+    },
+    AdminActionTaken {
+        admin: Principal,
+        action: AdminAction,
+        reason: String,
+    },
+}
+
+impl ShipmentEvent {
+    /// The shipment the event is about, if any.
+    pub fn shipment_id(&self) -> Option<ShipmentIdInner> {
+        match self {
+            Self::Created { shipment_id }
+            | Self::StatusUpdated { shipment_id, .. }
+            | Self::CarrierAssigned { shipment_id, .. }
+            | Self::Finalized { shipment_id }
+            | Self::SlaBreached { shipment_id, .. }
+            | Self::PickupTimedOut { shipment_id, .. }
+            | Self::Expired { shipment_id, .. }
+            | Self::Settled { shipment_id, .. }
+            | Self::DisputeOpened { shipment_id, .. }
+            | Self::DisputeResolved { shipment_id, .. }
+            | Self::Rated { shipment_id, .. }
+            | Self::FeeCharged { shipment_id, .. }
+            | Self::LegAssigned { shipment_id, .. }
+            | Self::HandoffConfirmed { shipment_id, .. }
+            | Self::LocationUpdated { shipment_id, .. }
+            | Self::ProcessingRecovered { shipment_id, .. } => Some(*shipment_id),
+            _ => None,
+        }
+    }
+
+    /// The account the event is about, for events not tied to a shipment.
+    pub fn account(&self) -> Option<Principal> {
+        match self {
+            Self::CollateralDeposited { carrier, .. }
+            | Self::CollateralWithdrawn { carrier, .. }
+            | Self::CarrierRegistered { carrier }
+            | Self::VehiclesUpdated { carrier } => Some(*carrier),
+            Self::CustomerRegistered { customer } => Some(*customer),
+            Self::ProfileUpdated { principal } => Some(*principal),
+            _ => None,
+        }
+    }
+}
+
+/// Interventions admins take outside the regular shipment lifecycle.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum AdminAction {
+    ForceStatus {
+        shipment_id: ShipmentIdInner,
+        from: ShipmentStatus,
+        to: ShipmentStatus,
+    },
+    ReassignCarrier {
+        shipment_id: ShipmentIdInner,
+        from: Principal,
+        to: Principal,
+    },
+    RemoveListing {
+        shipment_id: ShipmentIdInner,
+    },
+    Ban {
+        principal: Principal,
+    },
+    Unban {
+        principal: Principal,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MarketplaceListing {
+    pub shipment: ShipmentListing,
+    pub customer: Option<ReputationSummary>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AccountProfiles {
+    pub customer: Option<Profile>,
+    pub carrier: Option<Profile>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct CarrierFleet {
+    pub vehicles: Vec<Vehicle>,
+    pub load: CargoLoad,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Tracking {
+    pub latest: Option<LocationPoint>,
+    pub trail: Vec<LocationPoint>,
+    pub proof_of_delivery: Option<ProofOfDelivery>,
+    /// Operation currently in flight on the shipment.
+    pub processing: Option<Processing>,
+}
+
+/// Everything an arbiter needs to decide a dispute.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DisputeCase {
+    pub dispute: Dispute,
+    pub proof_of_delivery: Option<ProofOfDelivery>,
+    pub trail: Vec<LocationPoint>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct NewShipment {
+    pub name: String,
+    pub hashed_secret: String,
+    pub info: ShipmentInfo,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -40,43 +275,33 @@ pub struct TimestampedEvent {
 }
 
 thread_local! {
-    static EVENTS: RefCell<VecDeque<TimestampedEvent>> = RefCell::new(VecDeque::new());
-    static LAST_SEQUENCE: RefCell<u64> = RefCell::new(0);
+    static EVENTS: RefCell<VecDeque<TimestampedEvent>> = const { RefCell::new(VecDeque::new()) };
+    static LAST_SEQUENCE: RefCell<u64> = const { RefCell::new(0) };
     static ADMINS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static ARBITERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static AUDITORS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+    static BANNED: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
 }
 
 const MAX_EVENTS_AGE: u64 = 24 * 60 * 60; // 24 hours in seconds
 const MAX_EVENTS_SIZE: usize = 1000;
 
-fn check_anonymous(caller: Principal) -> Result<(), String> {
-    if caller == Principal::anonymous() {
-        return Err("Cannot be called anonymously".to_string());
-    }
-
-    Ok(())
-}
-
-fn check_admin(caller: Principal) -> Result<(), String> {
-    if !ADMINS.with_borrow(|admins| admins.contains(&caller)) {
-        return Err("Cannot be called by non-admins".to_string());
-    }
-
-    Ok(())
-}
-
 #[init]
 fn init() {
     ic_cdk::print("Initializing the shipment service");
 
+    ADMINS.with_borrow_mut(|admins| admins.insert(CanisterEnv.caller()));
+    jobs::start();
+
     // Create a default customer
     let mut default_customer = Customer::new(
         Principal::from_text("ryssj-xcbz7-gbw4s-p7fio-lolnx-5nr7a-yxufe-cvpfg-6iujw-2ypsz-rqe")
             .unwrap(),
-        "Test".to_string(),
+        Profile::new("Test".to_string()),
     );
 
     // Define a set of realistic coordinates for shipment locations
-    let locations = vec![
+    let locations = [
         ("A", 40.7128, -74.0060),  // New York, USA
         ("B", 34.0522, -118.2437), // Los Angeles, USA
         ("C", 51.5074, -0.1278),   // London, UK
@@ -85,7 +310,7 @@ fn init() {
         ("F", -33.8688, 151.2093), // Sydney, Australia
     ];
 
-    let names = vec![
+    let names = [
         "John Doe",
         "Jane Doe",
         "Alice Smith",
@@ -99,13 +324,14 @@ fn init() {
     ];
 
     for i in 0..10 {
-        let shipment_id = ShipmentId::new();
+        let shipment_id = ShipmentId::new(&CanisterEnv);
         let inner_shipment_id = shipment_id.into_inner();
 
         let (origin_label, origin_lat, origin_lng) = &locations[i % locations.len()];
         let (dest_label, dest_lat, dest_lng) = &locations[(i + 1) % locations.len()];
 
         let shipment = Shipment::create(
+            &CanisterEnv,
             &mut default_customer,
             inner_shipment_id,
             "hashed_secret".to_string(),
@@ -122,152 +348,475 @@ fn init() {
         // Insert the shipment into the SHIPMENTS collection
         SHIPMENTS.with_borrow_mut(|shipments| shipments.insert(inner_shipment_id, shipment));
     }
+
+    CUSTOMERS
+        .with_borrow_mut(|customers| customers.insert(default_customer.id(), default_customer));
 }
 
 #[update(name = "finalizeShipment")]
 async fn finalize_shipment(
     shipment_id: ShipmentIdInner,
     secret_key: Option<String>,
+    proof: Option<ProofOfDelivery>,
 ) -> Result<(), String> {
-    let caller = ic_cdk::caller();
-    let (finalize_result, _carrier, _value, _price) = SHIPMENTS
-        .with_borrow_mut(|shipments| {
-            let shipment = shipments
-                .get_mut(&shipment_id)
-                .ok_or(anyhow!("Shipment not found"))?;
-
-            if ic_cdk::caller() != shipment.carrier_id().ok_or(anyhow!("Carrier not set"))? {
-                return Err(anyhow!("Caller is not the carrier"));
-            }
+    api::finalize_shipment(&CanisterEnv, shipment_id, secret_key, proof)
+}
 
-            CUSTOMERS.with_borrow_mut(|customers| {
-                let customer = customers
-                    .get_mut(&shipment.customer_id())
-                    .ok_or(anyhow!("Customer not found"))?;
-
-                CARRIERS.with_borrow_mut(|carriers| {
-                    let carrier = carriers
-                        .get_mut(&shipment.carrier_id().ok_or(anyhow!("Carrier not set"))?)
-                        .ok_or(anyhow!("Carrier not found"))?;
-
-                    Ok((
-                        shipment.finalize(carrier, customer, secret_key, caller),
-                        carrier.id(),
-                        shipment.info().value(),
-                        shipment.info().price(),
-                    ))
-                })
-            })
-        })
-        .map_err(|e: anyhow::Error| e.to_string())?;
+#[update(name = "finalizeShipments")]
+async fn finalize_shipments(
+    items: Vec<(ShipmentIdInner, Option<String>, Option<ProofOfDelivery>)>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<(), String>>, String> {
+    api::finalize_shipments(&CanisterEnv, items, all_or_nothing)
+}
 
-    if finalize_result.is_ok() {
-        add_event(ShipmentEvent::Finalized { shipment_id });
-    }
+#[update(name = "openDispute")]
+async fn open_dispute(
+    shipment_id: ShipmentIdInner,
+    reason: String,
+    evidence_hash: String,
+) -> Result<(), String> {
+    api::open_dispute(&CanisterEnv, shipment_id, reason, evidence_hash)
+}
+
+#[update(name = "rateShipment")]
+async fn rate_shipment(
+    shipment_id: ShipmentIdInner,
+    score: u8,
+    tags: Vec<String>,
+    carrier: Option<Principal>,
+) -> Result<(), String> {
+    api::rate_shipment(&CanisterEnv, shipment_id, score, tags, carrier)
+}
+
+#[update(name = "setRecipient")]
+async fn set_recipient(
+    shipment_id: ShipmentIdInner,
+    recipient: Option<Principal>,
+) -> Result<(), String> {
+    api::set_recipient(&CanisterEnv, shipment_id, recipient)
+}
+
+#[update(name = "resolveDispute")]
+async fn resolve_dispute(
+    shipment_id: ShipmentIdInner,
+    outcome: DisputeOutcome,
+) -> Result<Settlement, String> {
+    api::resolve_dispute(&CanisterEnv, shipment_id, outcome)
+}
 
-    finalize_result.map_err(|e| e.to_string())
+#[query(name = "getDisputeCase")]
+fn get_dispute_case(shipment_id: ShipmentIdInner) -> Result<DisputeCase, String> {
+    api::get_dispute_case(&CanisterEnv, shipment_id)
 }
 
 #[update(name = "buyShipment")]
-async fn buy_shipment(carrier_name: String, shipment_id: ShipmentIdInner) -> Result<(), String> {
-    let carrier_id = ic_cdk::caller();
-    check_anonymous(carrier_id)?;
+async fn buy_shipment(shipment_id: ShipmentIdInner) -> Result<(), String> {
+    api::buy_shipment(&CanisterEnv, shipment_id)
+}
 
-    let (buy_result, _amount) = CARRIERS
-        .with_borrow_mut(|carriers| {
-            let carrier = carriers.get_or_create(carrier_id, carrier_name);
+#[update(name = "buyShipments")]
+async fn buy_shipments(
+    shipment_ids: Vec<ShipmentIdInner>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<(), String>>, String> {
+    api::buy_shipments(&CanisterEnv, shipment_ids, all_or_nothing)
+}
 
-            SHIPMENTS.with_borrow_mut(|shipments| {
-                let shipment = shipments
-                    .get_mut(&shipment_id)
-                    .ok_or(anyhow!("Shipment not found"))?;
+#[update(name = "buyShipmentLeg")]
+async fn buy_shipment_leg(shipment_id: ShipmentIdInner, leg: u32) -> Result<(), String> {
+    api::buy_shipment_leg(&CanisterEnv, shipment_id, leg)
+}
 
-                Ok((shipment.buy(carrier), shipment.info().value()))
-            })
+#[update(name = "pickupShipment")]
+async fn pickup_shipment(
+    shipment_id: ShipmentIdInner,
+    position: Option<LocationPoint>,
+) -> Result<(), String> {
+    api::pickup_shipment(&CanisterEnv, shipment_id, position)
+}
+
+#[update(name = "confirmHandoff")]
+async fn confirm_handoff(shipment_id: ShipmentIdInner) -> Result<Settlement, String> {
+    api::confirm_handoff(&CanisterEnv, shipment_id)
+}
+
+/// Adds the position of the calling carrier to the trail of every shipment it
+/// is carrying right now.
+#[update(name = "reportLocation")]
+async fn report_location(lat: f64, lng: f64, accuracy: f64) -> Result<(), String> {
+    api::report_location(&CanisterEnv, lat, lng, accuracy)
+}
+
+#[query(name = "getTracking")]
+fn get_tracking(shipment_id: ShipmentIdInner) -> Result<Tracking, String> {
+    api::get_tracking(&CanisterEnv, shipment_id)
+}
+
+#[update(name = "registerCustomer")]
+async fn register_customer(profile: Profile) -> Result<(), String> {
+    api::register_customer(&CanisterEnv, profile)
+}
+
+#[update(name = "registerCarrier")]
+async fn register_carrier(profile: Profile) -> Result<(), String> {
+    api::register_carrier(&CanisterEnv, profile)
+}
+
+/// Updates the profile of every account the caller has registered.
+#[update(name = "updateProfile")]
+async fn update_profile(profile: Profile) -> Result<(), String> {
+    api::update_profile(&CanisterEnv, profile)
+}
+
+#[query(name = "getMyProfile")]
+fn get_my_profile() -> AccountProfiles {
+    api::get_my_profile(&CanisterEnv)
+}
+
+#[update(name = "setVehicles")]
+async fn set_vehicles(vehicles: Vec<Vehicle>) -> Result<(), String> {
+    api::set_vehicles(&CanisterEnv, vehicles)
+}
+
+#[query(name = "getCarrierFleet")]
+fn get_carrier_fleet(carrier_id: Principal) -> Option<CarrierFleet> {
+    CARRIERS.with_borrow(|carriers| {
+        carriers.get(&carrier_id).map(|carrier| CarrierFleet {
+            vehicles: carrier.vehicles().to_vec(),
+            load: carrier.load(),
         })
-        .map_err(|e: anyhow::Error| e.to_string())?;
+    })
+}
 
-    if buy_result.is_ok() {
-        add_event(ShipmentEvent::CarrierAssigned {
-            shipment_id,
-            carrier: carrier_id,
-        });
+#[update(name = "depositCollateral")]
+async fn deposit_collateral(amount: u64) -> Result<(), String> {
+    let _guard = CollateralGuard::acquire(CanisterEnv.caller()).map_err(|e| e.to_string())?;
+    let ledger = api::check_deposit(&CanisterEnv, amount)?;
+    ledger::transfer_from(ledger, CanisterEnv.caller(), amount).await?;
+
+    let deposit = api::deposit_collateral(&CanisterEnv, amount);
+    if deposit.is_err() {
+        // Not expected after `check_deposit` under the guard, but the tokens
+        // are already taken, so they go back.
+        let _ = ledger::transfer(ledger, CanisterEnv.caller(), amount).await;
     }
 
-    buy_result.map_err(|e| e.to_string())
+    deposit
+}
+
+#[update(name = "withdrawCollateral")]
+async fn withdraw_collateral(amount: u64) -> Result<(), String> {
+    let _guard = CollateralGuard::acquire(CanisterEnv.caller()).map_err(|e| e.to_string())?;
+    let ledger = api::withdraw_collateral(&CanisterEnv, amount)?;
+    let transfer = ledger::transfer(ledger, CanisterEnv.caller(), amount).await;
+
+    api::finish_withdrawal(&CanisterEnv, amount, transfer)
 }
 
 #[update(name = "createShipment")]
 async fn create_shipment(
-    customer_name: String,
     shipment_name: String,
     hashed_secret: String,
     shipment_info: ShipmentInfo,
 ) -> Result<ShipmentIdInner, String> {
-    let customer_id = ic_cdk::caller();
-    check_anonymous(customer_id)?;
-
-    let shipment_id = CUSTOMERS.with_borrow_mut(|customers| {
-        let customer = customers.get_or_create(customer_name, customer_id);
-        let shipment_id = ShipmentId::new();
-        let inner_shipment_id = shipment_id.into_inner();
-        let shipment = Shipment::create(
-            customer,
-            inner_shipment_id,
-            hashed_secret,
-            shipment_name,
-            shipment_info,
-        );
-        SHIPMENTS.with_borrow_mut(|shipments| shipments.insert(inner_shipment_id, shipment));
-
-        add_event(ShipmentEvent::Created {
-            shipment_id: inner_shipment_id,
-        });
+    api::create_shipment(&CanisterEnv, shipment_name, hashed_secret, shipment_info)
+}
 
-        inner_shipment_id
-    });
+#[update(name = "createShipments")]
+async fn create_shipments(
+    items: Vec<NewShipment>,
+    all_or_nothing: bool,
+) -> Result<Vec<Result<ShipmentIdInner, String>>, String> {
+    api::create_shipments(&CanisterEnv, items, all_or_nothing)
+}
 
-    Ok(shipment_id)
+#[query(name = "quoteShipment")]
+fn quote_shipment(shipment_info: ShipmentInfo) -> Result<Quote, String> {
+    shipment_info
+        .size_category()
+        .validate()
+        .map_err(|e| e.to_string())?;
+    shipment_info
+        .validate_locations()
+        .map_err(|e| e.to_string())?;
+
+    PRICING_CONFIG
+        .with_borrow(|config| config.quote(&shipment_info))
+        .map_err(|e| e.to_string())
 }
 
 #[query(name = "listPendingShipments")]
 fn get_pending_shipments() -> Vec<Shipment> {
-    SHIPMENTS.with_borrow(|shipments| shipments.get_all_pending())
+    api::get_pending_shipments(&CanisterEnv)
+}
+
+#[query(name = "listMarketplace")]
+fn list_marketplace() -> Vec<MarketplaceListing> {
+    let pending = SHIPMENTS.with_borrow(|shipments| shipments.get_all_pending());
+
+    CUSTOMERS.with_borrow(|customers| {
+        pending
+            .into_iter()
+            .map(|shipment| MarketplaceListing {
+                customer: customers
+                    .get(&shipment.customer_id())
+                    .map(|customer| customer.reputation()),
+                shipment: shipment.listing(),
+            })
+            .collect()
+    })
+}
+
+#[query(name = "getCarrierReputation")]
+fn get_carrier_reputation(carrier_id: Principal) -> Option<ReputationSummary> {
+    CARRIERS.with_borrow(|carriers| {
+        carriers
+            .get(&carrier_id)
+            .map(|carrier| carrier.reputation())
+    })
+}
+
+#[query(name = "getCustomerReputation")]
+fn get_customer_reputation(customer_id: Principal) -> Option<ReputationSummary> {
+    CUSTOMERS.with_borrow(|customers| {
+        customers
+            .get(&customer_id)
+            .map(|customer| customer.reputation())
+    })
 }
 
 #[query(name = "listUserShipments")]
 fn get_user_shipments() -> (Vec<Shipment>, Vec<Shipment>) {
-    let customer_id = ic_cdk::caller();
-
-    let shippers = SHIPMENTS.with_borrow(|shipments| shipments.get_all_for_shipper(&customer_id));
-    let customers = SHIPMENTS.with_borrow(|shipments| shipments.get_all_for_customer(&customer_id));
-    (shippers, customers)
+    api::get_user_shipments(&CanisterEnv)
 }
 
 #[query]
 fn roles() -> (bool, bool) {
-    let carrier = CARRIERS.with_borrow(|carriers| carriers.contains_key(&ic_cdk::caller()));
-    let customer = CUSTOMERS.with_borrow(|customers| customers.contains_key(&ic_cdk::caller()));
-
-    (carrier, customer)
+    api::roles(&CanisterEnv)
 }
 
 #[query]
-fn shipments() -> Vec<Shipment> {
-    SHIPMENTS.with_borrow(|shipments| shipments.values().cloned().collect())
+fn shipments() -> Result<Vec<Shipment>, String> {
+    api::shipments(&CanisterEnv)
 }
 
 #[query(name = "getShipment")]
 fn get_shipment(shipment_id: ShipmentIdInner) -> Option<Shipment> {
-    SHIPMENTS.with_borrow(|shipments| shipments.get(&shipment_id).cloned())
+    api::get_shipment(&CanisterEnv, shipment_id)
+}
+
+#[query(name = "getSlaConfig")]
+fn get_sla_config() -> SlaConfig {
+    SLA_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setSlaConfig")]
+fn set_sla_config(config: SlaConfig) -> Result<(), String> {
+    api::set_sla_config(&CanisterEnv, config)
+}
+
+#[query(name = "getExpiryConfig")]
+fn get_expiry_config() -> ExpiryConfig {
+    EXPIRY_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setExpiryConfig")]
+fn set_expiry_config(config: ExpiryConfig) -> Result<(), String> {
+    api::set_expiry_config(&CanisterEnv, config)
+}
+
+#[query(name = "getMode")]
+fn get_mode() -> OperationalMode {
+    MODE.with_borrow(|mode| *mode)
+}
+
+/// Always allowed, so that admins can leave read-only mode again.
+#[update(name = "setMode")]
+fn set_mode(mode: OperationalMode) -> Result<(), String> {
+    api::set_mode(&CanisterEnv, mode)
+}
+
+#[query(name = "getMetrics")]
+fn get_metrics() -> Result<Metrics, String> {
+    api::get_metrics(&CanisterEnv)
+}
+
+#[query(name = "getQuotaConfig")]
+fn get_quota_config() -> QuotaConfig {
+    QUOTA_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setQuotaConfig")]
+fn set_quota_config(config: QuotaConfig) -> Result<(), String> {
+    api::set_quota_config(&CanisterEnv, config)
+}
+
+#[query(name = "getGeofenceConfig")]
+fn get_geofence_config() -> GeofenceConfig {
+    GEOFENCE_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setGeofenceConfig")]
+fn set_geofence_config(config: GeofenceConfig) -> Result<(), String> {
+    api::set_geofence_config(&CanisterEnv, config)
+}
+
+#[query(name = "getCollateralConfig")]
+fn get_collateral_config() -> CollateralConfig {
+    COLLATERAL_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setCollateralConfig")]
+fn set_collateral_config(config: CollateralConfig) -> Result<(), String> {
+    api::set_collateral_config(&CanisterEnv, config)
+}
+
+#[query(name = "getPricingConfig")]
+fn get_pricing_config() -> PricingConfig {
+    PRICING_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setPricingConfig")]
+fn set_pricing_config(config: PricingConfig) -> Result<(), String> {
+    api::set_pricing_config(&CanisterEnv, config)
+}
+
+#[query(name = "getFeeConfig")]
+fn get_fee_config() -> FeeConfig {
+    FEE_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setFeeConfig")]
+fn set_fee_config(config: FeeConfig) -> Result<(), String> {
+    api::set_fee_config(&CanisterEnv, config)
+}
+
+#[query(name = "getFeeLedger")]
+fn get_fee_ledger() -> Result<FeeLedger, String> {
+    api::get_fee_ledger(&CanisterEnv)
 }
 
-fn add_event(event: ShipmentEvent) {
+/// Sends platform fees to `to` through the collateral ledger.
+#[update(name = "withdrawFees")]
+async fn withdraw_fees(amount: u64, to: Principal) -> Result<(), String> {
+    let ledger = api::withdraw_fees(&CanisterEnv, amount)?;
+    let transfer = ledger::transfer(ledger, to, amount).await;
+
+    api::finish_fee_withdrawal(&CanisterEnv, amount, to, transfer)
+}
+
+#[query(name = "getDisputeConfig")]
+fn get_dispute_config() -> DisputeConfig {
+    DISPUTE_CONFIG.with_borrow(|config| config.clone())
+}
+
+#[update(name = "setDisputeConfig")]
+fn set_dispute_config(config: DisputeConfig) -> Result<(), String> {
+    api::set_dispute_config(&CanisterEnv, config)
+}
+
+#[update(name = "addArbiter")]
+fn add_arbiter(arbiter: Principal) -> Result<(), String> {
+    api::add_arbiter(&CanisterEnv, arbiter)
+}
+
+#[update(name = "removeArbiter")]
+fn remove_arbiter(arbiter: Principal) -> Result<(), String> {
+    api::remove_arbiter(&CanisterEnv, arbiter)
+}
+
+#[update(name = "addAuditor")]
+fn add_auditor(auditor: Principal) -> Result<(), String> {
+    api::add_auditor(&CanisterEnv, auditor)
+}
+
+#[update(name = "removeAuditor")]
+fn remove_auditor(auditor: Principal) -> Result<(), String> {
+    api::remove_auditor(&CanisterEnv, auditor)
+}
+
+/// Moves a stuck or misreported shipment to `status`, e.g. `Delivered` when
+/// the parties confirmed delivery out of band.
+#[update(name = "forceShipmentStatus")]
+fn force_shipment_status(
+    shipment_id: ShipmentIdInner,
+    status: ShipmentStatus,
+    reason: String,
+) -> Result<(), String> {
+    api::force_shipment_status(&CanisterEnv, shipment_id, status, reason)
+}
+
+/// Hands the active leg of a shipment over to another registered carrier.
+#[update(name = "reassignCarrier")]
+fn reassign_carrier(
+    shipment_id: ShipmentIdInner,
+    carrier_id: Principal,
+    reason: String,
+) -> Result<(), String> {
+    api::reassign_carrier(&CanisterEnv, shipment_id, carrier_id, reason)
+}
+
+/// Takes an abusive listing off the marketplace for good, refunding the
+/// customer like an expiry would.
+#[update(name = "removeShipment")]
+fn remove_shipment(shipment_id: ShipmentIdInner, reason: String) -> Result<(), String> {
+    api::remove_shipment(&CanisterEnv, shipment_id, reason)
+}
+
+#[query(name = "getBannedPrincipals")]
+fn get_banned_principals() -> Result<Vec<Principal>, String> {
+    api::get_banned_principals(&CanisterEnv)
+}
+
+/// Banned principals can no longer create or buy shipments, those under way
+/// are not affected.
+#[update(name = "banPrincipal")]
+fn ban_principal(principal: Principal, reason: String) -> Result<(), String> {
+    api::ban_principal(&CanisterEnv, principal, reason)
+}
+
+#[update(name = "unbanPrincipal")]
+fn unban_principal(principal: Principal, reason: String) -> Result<(), String> {
+    api::unban_principal(&CanisterEnv, principal, reason)
+}
+
+/// Books the platform fee of a settlement and emits the matching events.
+fn record_settlement(
+    env: &impl Environment,
+    shipment_id: ShipmentIdInner,
+    leg: Option<u32>,
+    settlement: Settlement,
+) {
+    let platform_fee = settlement.platform_fee;
+
+    record_event(
+        env,
+        ShipmentEvent::Settled {
+            shipment_id,
+            leg,
+            settlement,
+        },
+    );
+
+    if platform_fee > 0 {
+        FEE_LEDGER.with_borrow_mut(|ledger| ledger.accrue(platform_fee));
+        record_event(
+            env,
+            ShipmentEvent::FeeCharged {
+                shipment_id,
+                amount: platform_fee,
+            },
+        );
+    }
+}
+
+fn record_event(env: &impl Environment, event: ShipmentEvent) {
     LAST_SEQUENCE.with(|seq| {
         let next_seq = *seq.borrow() + 1;
         *seq.borrow_mut() = next_seq;
 
-        let time_nanos = ic_cdk::api::time();
+        let time_nanos = env.now();
         let timestamp = time_nanos / 1_000_000_000;
         let timestamped = TimestampedEvent {
             event,
@@ -289,33 +838,12 @@ fn add_event(event: ShipmentEvent) {
 
 #[query(name = "getEvents")]
 fn get_events(since_sequence: Option<u64>) -> Vec<TimestampedEvent> {
-    EVENTS.with(|events| {
-        events
-            .borrow()
-            .iter()
-            .filter(|e| match since_sequence {
-                Some(seq) => e.sequence > seq,
-                None => true,
-            })
-            .cloned()
-            .collect()
-    })
+    api::get_events(&CanisterEnv, since_sequence)
 }
 
 #[update(name = "purgeOldEvents")]
 fn purge_old_events() -> Result<(), String> {
-    check_admin(ic_cdk::caller())?;
-
-    let current_time = ic_cdk::api::time();
-    let current_time_secs = current_time / 1_000_000_000;
-
-    EVENTS.with(|events| {
-        events
-            .borrow_mut()
-            .retain(|e| current_time_secs - e.timestamp < MAX_EVENTS_AGE);
-    });
-
-    Ok(())
+    api::purge_old_events(&CanisterEnv)
 }
 
 ic_cdk::export_candid!();
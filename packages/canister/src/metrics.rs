@@ -0,0 +1,177 @@
+use crate::{
+    models::shipment::ShipmentStatus,
+    state::{CARRIERS, CUSTOMERS, LAST_JOBS_RUN, SHIPMENTS},
+    EVENTS,
+};
+use candid::{CandidType, Deserialize};
+use std::fmt::Write;
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+const STATUSES: [ShipmentStatus; 6] = [
+    ShipmentStatus::Pending,
+    ShipmentStatus::Bought,
+    ShipmentStatus::InTransit,
+    ShipmentStatus::Delivered,
+    ShipmentStatus::Disputed,
+    ShipmentStatus::Cancelled,
+];
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Metrics {
+    pub cycles_balance: u128,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub shipments: Vec<(ShipmentStatus, u64)>,
+    pub customers: u64,
+    pub carriers: u64,
+    pub events: u64,
+    pub oldest_event_sequence: Option<u64>,
+    /// Nanoseconds since the epoch, `None` until the jobs ran once after install.
+    pub last_jobs_run: Option<u64>,
+}
+
+impl Metrics {
+    pub fn collect() -> Self {
+        let shipments = SHIPMENTS.with_borrow(|shipments| {
+            STATUSES
+                .into_iter()
+                .map(|status| {
+                    let count = shipments
+                        .values()
+                        .filter(|shipment| *shipment.status() == status)
+                        .count();
+                    (status, count as u64)
+                })
+                .collect()
+        });
+
+        let (events, oldest_event_sequence) = EVENTS.with_borrow(|events| {
+            (
+                events.len() as u64,
+                events.front().map(|event| event.sequence),
+            )
+        });
+
+        Self {
+            cycles_balance: ic_cdk::api::canister_balance128(),
+            heap_memory_bytes: heap_memory_bytes(),
+            stable_memory_bytes: ic_cdk::api::stable::stable_size() * WASM_PAGE_SIZE,
+            shipments,
+            customers: CUSTOMERS.with_borrow(|customers| customers.len() as u64),
+            carriers: CARRIERS.with_borrow(|carriers| carriers.len() as u64),
+            events,
+            oldest_event_sequence,
+            last_jobs_run: LAST_JOBS_RUN.with_borrow(|last| *last),
+        }
+    }
+
+    /// Prometheus text exposition format, version 0.0.4.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "canister_cycles_balance",
+            "Cycles balance of the canister.",
+            self.cycles_balance,
+        );
+        gauge(
+            &mut out,
+            "canister_heap_memory_bytes",
+            "Size of the wasm heap.",
+            self.heap_memory_bytes,
+        );
+        gauge(
+            &mut out,
+            "canister_stable_memory_bytes",
+            "Size of the stable memory.",
+            self.stable_memory_bytes,
+        );
+
+        header(&mut out, "shipments", "Shipments by status.");
+        for (status, count) in &self.shipments {
+            let _ = writeln!(out, "shipments{{status=\"{status:?}\"}} {count}");
+        }
+
+        gauge(
+            &mut out,
+            "customers",
+            "Registered customers.",
+            self.customers,
+        );
+        gauge(&mut out, "carriers", "Registered carriers.", self.carriers);
+        gauge(&mut out, "events", "Events in the log.", self.events);
+
+        if let Some(sequence) = self.oldest_event_sequence {
+            gauge(
+                &mut out,
+                "events_oldest_sequence",
+                "Sequence of the oldest event in the log.",
+                sequence,
+            );
+        }
+
+        if let Some(last) = self.last_jobs_run {
+            gauge(
+                &mut out,
+                "jobs_last_run_timestamp_seconds",
+                "Last run of the periodic jobs.",
+                last / 1_000_000_000,
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    header(out, name, help);
+    let _ = writeln!(out, "{name} {value}");
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_format() {
+        let metrics = Metrics {
+            cycles_balance: 1_000,
+            heap_memory_bytes: 2 * WASM_PAGE_SIZE,
+            stable_memory_bytes: 0,
+            shipments: vec![(ShipmentStatus::Pending, 3), (ShipmentStatus::InTransit, 1)],
+            customers: 2,
+            carriers: 1,
+            events: 10,
+            oldest_event_sequence: Some(4),
+            last_jobs_run: None,
+        };
+
+        let text = metrics.to_prometheus();
+
+        assert!(
+            text.contains("# TYPE canister_cycles_balance gauge\ncanister_cycles_balance 1000\n")
+        );
+        assert!(text.contains("shipments{status=\"Pending\"} 3\n"));
+        assert!(text.contains("shipments{status=\"InTransit\"} 1\n"));
+        assert!(text.contains("events_oldest_sequence 4\n"));
+        assert!(!text.contains("jobs_last_run"));
+    }
+}
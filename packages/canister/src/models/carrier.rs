@@ -1,26 +1,48 @@
 #![allow(unused)]
 
-use super::shipment_id::ShipmentIdInner;
-use candid::Principal;
+use super::{
+    profile::Profile,
+    reputation::{Rating, Reputation, ReputationSummary},
+    shipment::ShipmentInfo,
+    shipment_id::ShipmentIdInner,
+    vehicle::{CargoLoad, Vehicle, MAX_VEHICLES},
+};
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
 pub type CarrierId = Principal;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
 pub struct Carrier {
     id: CarrierId,
-    name: String,
+    profile: Profile,
     shipments_done: u32,
     shipments: Vec<ShipmentIdInner>,
+    reputation: Reputation,
+    vehicles: Vec<Vehicle>,
+    /// What the shipments in `shipments` take up across all vehicles.
+    load: CargoLoad,
+    /// Collateral transferred in from the ledger. Locked and slashed amounts
+    /// are taken out of it and cannot be withdrawn.
+    deposited_collateral: u64,
+    /// Sum of shipment values the carrier has put down as deposit for active shipments.
+    locked_collateral: u64,
+    slashed_collateral: u64,
 }
 
 impl Carrier {
-    pub fn new(id: CarrierId, name: String) -> Self {
+    pub fn new(id: CarrierId, profile: Profile) -> Self {
         Self {
             id,
-            name,
+            profile,
             shipments: vec![],
             shipments_done: 0,
+            reputation: Reputation::default(),
+            vehicles: vec![],
+            load: CargoLoad::default(),
+            deposited_collateral: 0,
+            locked_collateral: 0,
+            slashed_collateral: 0,
         }
     }
 
@@ -28,17 +50,164 @@ impl Carrier {
         self.shipments.push(shipment_id);
     }
 
-    pub fn finalize_shipment(&mut self, shipment_id: ShipmentIdInner) {
+    pub fn finalize_shipment(&mut self, shipment_id: ShipmentIdInner, on_time: bool) {
         self.shipments.retain(|&x| x != shipment_id);
         self.shipments_done += 1;
+        self.reputation.record_delivery(on_time);
     }
-    
+
+    pub fn drop_shipment(&mut self, shipment_id: ShipmentIdInner) {
+        self.shipments.retain(|&x| x != shipment_id);
+    }
+
+    pub fn set_vehicles(&mut self, vehicles: Vec<Vehicle>) -> anyhow::Result<()> {
+        if vehicles.len() > MAX_VEHICLES {
+            return Err(anyhow::anyhow!("too many vehicles"));
+        }
+
+        for vehicle in &vehicles {
+            vehicle.validate()?;
+        }
+
+        self.vehicles = vehicles;
+
+        Ok(())
+    }
+
+    /// Checks that the shipment fits into one of the vehicles and that the
+    /// fleet still has room for it next to the shipments already held.
+    /// Envelopes are not measured but their weight still counts, so a carrier
+    /// without vehicles can only take envelopes with no declared weight.
+    pub fn check_capacity(&self, info: &ShipmentInfo) -> anyhow::Result<()> {
+        let boxes = info.size_category().boxes();
+        if !boxes.is_empty() && self.vehicles.is_empty() {
+            return Err(anyhow::anyhow!("carrier has no vehicles to carry parcels"));
+        }
+
+        let fits = |dimensions: &[u64; 3]| self.vehicles.iter().any(|v| v.fits(*dimensions));
+        if !boxes.iter().all(fits) {
+            return Err(anyhow::anyhow!(
+                "shipment does not fit into any of the vehicles"
+            ));
+        }
+
+        let needed = info.cargo_load();
+        let mut load = self.load;
+        load.add(needed);
+
+        let volume: u64 = self.vehicles.iter().map(Vehicle::volume).sum();
+        let max_load: u64 = self
+            .vehicles
+            .iter()
+            .map(|vehicle| vehicle.max_load_grams)
+            .sum();
+
+        if load.volume > volume {
+            return Err(anyhow::anyhow!("not enough cargo space left"));
+        }
+
+        if load.weight_grams > max_load {
+            return Err(anyhow::anyhow!("shipment exceeds the remaining load limit"));
+        }
+
+        Ok(())
+    }
+
+    pub fn load_cargo(&mut self, load: CargoLoad) {
+        self.load.add(load);
+    }
+
+    pub fn unload_cargo(&mut self, load: CargoLoad) {
+        self.load.remove(load);
+    }
+
+    pub fn vehicles(&self) -> &[Vehicle] {
+        &self.vehicles
+    }
+
+    pub fn load(&self) -> CargoLoad {
+        self.load
+    }
+
+    /// Checks that a deposit of `amount` can be credited.
+    pub fn check_deposit(&self, amount: u64) -> anyhow::Result<()> {
+        self.deposited_collateral
+            .checked_add(amount)
+            .map(|_| ())
+            .ok_or(anyhow::anyhow!("collateral overflow"))
+    }
+
+    pub fn deposit_collateral(&mut self, amount: u64) -> anyhow::Result<()> {
+        self.check_deposit(amount)?;
+        self.deposited_collateral += amount;
+
+        Ok(())
+    }
+
+    pub fn withdraw_collateral(&mut self, amount: u64) -> anyhow::Result<()> {
+        if amount > self.free_collateral() {
+            return Err(anyhow::anyhow!("not enough free collateral"));
+        }
+
+        self.deposited_collateral -= amount;
+
+        Ok(())
+    }
+
+    /// Puts `amount` of the free collateral down for a shipment.
+    pub fn lock_collateral(&mut self, amount: u64) -> anyhow::Result<()> {
+        if amount > self.free_collateral() {
+            return Err(anyhow::anyhow!("not enough free collateral"));
+        }
+
+        self.locked_collateral = self
+            .locked_collateral
+            .checked_add(amount)
+            .ok_or(anyhow::anyhow!("collateral overflow"))?;
+
+        Ok(())
+    }
+
+    pub fn release_collateral(&mut self, amount: u64) {
+        self.locked_collateral = self.locked_collateral.saturating_sub(amount);
+    }
+
+    pub fn slash_collateral(&mut self, amount: u64) -> anyhow::Result<()> {
+        self.slashed_collateral = self
+            .slashed_collateral
+            .checked_add(amount)
+            .ok_or(anyhow::anyhow!("collateral overflow"))?;
+        self.release_collateral(amount);
+
+        Ok(())
+    }
+
+    pub fn add_rating(&mut self, rating: &Rating) {
+        self.reputation.add_rating(rating);
+    }
+
+    pub fn record_dispute(&mut self) {
+        self.reputation.record_dispute();
+    }
+
+    pub fn reputation(&self) -> ReputationSummary {
+        self.reputation.summary(self.shipments_done)
+    }
+
     pub fn id(&self) -> Principal {
         self.id
     }
 
     pub fn name(&self) -> &str {
-        &self.name
+        &self.profile.display_name
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn update_profile(&mut self, profile: Profile) {
+        self.profile = profile;
     }
 
     pub fn shipments(&self) -> &[ShipmentIdInner] {
@@ -48,4 +217,179 @@ impl Carrier {
     pub fn shipments_done(&self) -> u32 {
         self.shipments_done
     }
-}
\ No newline at end of file
+
+    pub fn free_collateral(&self) -> u64 {
+        self.deposited_collateral
+            .saturating_sub(self.locked_collateral)
+            .saturating_sub(self.slashed_collateral)
+    }
+
+    pub fn locked_collateral(&self) -> u64 {
+        self.locked_collateral
+    }
+
+    pub fn slashed_collateral(&self) -> u64 {
+        self.slashed_collateral
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{profile::VehicleClass, size::SizeCategory},
+        test_utils::{carrier, location},
+    };
+
+    fn van() -> Vehicle {
+        Vehicle {
+            class: VehicleClass::Van,
+            cargo_width: 100,
+            cargo_height: 100,
+            cargo_depth: 150,
+            max_load_grams: 10_000,
+        }
+    }
+
+    fn shipment(size_category: SizeCategory, weight_grams: u64) -> ShipmentInfo {
+        ShipmentInfo::new(
+            100,
+            1_000,
+            location(52.2297, 21.0122),
+            location(50.0647, 19.945),
+            size_category,
+        )
+        .with_weight(weight_grams)
+    }
+
+    fn parcel(side: u64) -> SizeCategory {
+        SizeCategory::Parcel {
+            max_width: side,
+            max_height: side,
+            max_depth: side,
+        }
+    }
+
+    #[test]
+    fn test_envelope_weight_counts_against_the_load_limit() {
+        let mut carrier = carrier(2);
+        carrier
+            .check_capacity(&shipment(SizeCategory::Envelope, 0))
+            .unwrap();
+        assert!(carrier
+            .check_capacity(&shipment(SizeCategory::Envelope, 100))
+            .is_err());
+
+        carrier.set_vehicles(vec![van()]).unwrap();
+        carrier
+            .check_capacity(&shipment(SizeCategory::Envelope, 10_000))
+            .unwrap();
+        assert_eq!(
+            carrier
+                .check_capacity(&shipment(SizeCategory::Envelope, 10_001))
+                .unwrap_err()
+                .to_string(),
+            "shipment exceeds the remaining load limit"
+        );
+    }
+
+    #[test]
+    fn test_parcels_need_room_in_the_fleet() {
+        let mut carrier = carrier(2);
+        assert_eq!(
+            carrier
+                .check_capacity(&shipment(parcel(10), 0))
+                .unwrap_err()
+                .to_string(),
+            "carrier has no vehicles to carry parcels"
+        );
+
+        carrier.set_vehicles(vec![van()]).unwrap();
+        assert_eq!(
+            carrier
+                .check_capacity(&shipment(parcel(150), 0))
+                .unwrap_err()
+                .to_string(),
+            "shipment does not fit into any of the vehicles"
+        );
+
+        // Bulky but light, the load limit only sees the actual weight.
+        let info = shipment(parcel(100), 6_000);
+        assert_eq!(info.chargeable_weight_grams(), 200_000);
+        assert_eq!(info.cargo_load().weight_grams, 6_000);
+        carrier.check_capacity(&info).unwrap();
+        carrier.load_cargo(info.cargo_load());
+
+        assert_eq!(
+            carrier
+                .check_capacity(&shipment(parcel(10), 6_000))
+                .unwrap_err()
+                .to_string(),
+            "shipment exceeds the remaining load limit"
+        );
+        assert_eq!(
+            carrier
+                .check_capacity(&shipment(parcel(100), 0))
+                .unwrap_err()
+                .to_string(),
+            "not enough cargo space left"
+        );
+
+        carrier.unload_cargo(info.cargo_load());
+        carrier.check_capacity(&info).unwrap();
+    }
+
+    #[test]
+    fn test_locked_and_slashed_collateral_cannot_be_withdrawn() {
+        let mut carrier = carrier(2);
+        carrier.deposit_collateral(1_000).unwrap();
+        carrier.lock_collateral(600).unwrap();
+        carrier.slash_collateral(100).unwrap();
+
+        assert_eq!(carrier.locked_collateral(), 500);
+        assert_eq!(carrier.free_collateral(), 400);
+        assert!(carrier.withdraw_collateral(401).is_err());
+
+        carrier.withdraw_collateral(400).unwrap();
+        assert_eq!(carrier.free_collateral(), 0);
+
+        carrier.release_collateral(500);
+        assert_eq!(carrier.free_collateral(), 500);
+    }
+
+    #[test]
+    fn test_collateral_cannot_overflow() {
+        let mut carrier = carrier(2);
+        carrier.deposit_collateral(u64::MAX).unwrap();
+        carrier.lock_collateral(u64::MAX).unwrap();
+
+        assert!(carrier.deposit_collateral(1).is_err());
+        assert!(carrier.lock_collateral(1).is_err());
+        assert_eq!(carrier.locked_collateral(), u64::MAX);
+
+        carrier.slash_collateral(u64::MAX).unwrap();
+        assert_eq!(carrier.locked_collateral(), 0);
+        assert_eq!(carrier.slashed_collateral(), u64::MAX);
+        assert_eq!(carrier.free_collateral(), 0);
+    }
+
+    #[test]
+    fn test_locks_need_free_collateral() {
+        let mut carrier = carrier(2);
+        assert_eq!(
+            carrier.lock_collateral(1).unwrap_err().to_string(),
+            "not enough free collateral"
+        );
+
+        carrier.deposit_collateral(100).unwrap();
+        carrier.lock_collateral(60).unwrap();
+        assert!(carrier.lock_collateral(50).is_err());
+        carrier.lock_collateral(40).unwrap();
+        assert_eq!(carrier.free_collateral(), 0);
+
+        carrier.slash_collateral(30).unwrap();
+        carrier.release_collateral(70);
+        assert!(carrier.lock_collateral(71).is_err());
+        carrier.lock_collateral(70).unwrap();
+    }
+}
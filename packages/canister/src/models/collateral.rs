@@ -0,0 +1,17 @@
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// ICRC-2 ledger whose tokens back carrier collateral. Deposits are pulled
+/// from the carrier's account with `icrc2_transfer_from`, so carriers approve
+/// the canister as spender first.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct CollateralConfig {
+    pub ledger: Option<Principal>,
+}
+
+impl CollateralConfig {
+    pub fn ledger(&self) -> anyhow::Result<Principal> {
+        self.ledger
+            .ok_or(anyhow::anyhow!("collateral ledger is not configured"))
+    }
+}
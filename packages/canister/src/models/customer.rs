@@ -1,26 +1,32 @@
 #![allow(unused)]
 
-use super::shipment_id::ShipmentIdInner;
-use candid::Principal;
+use super::{
+    profile::Profile,
+    reputation::{Rating, Reputation, ReputationSummary},
+    shipment_id::ShipmentIdInner,
+};
+use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 
 pub type CustomerId = Principal;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
 pub struct Customer {
     id: CustomerId,
-    name: String,
+    profile: Profile,
     shipments_sent: u32,
     shipments: Vec<ShipmentIdInner>,
+    reputation: Reputation,
 }
 
 impl Customer {
-    pub fn new(id: CustomerId, name: String) -> Self {
+    pub fn new(id: CustomerId, profile: Profile) -> Self {
         Self {
             id,
-            name,
+            profile,
             shipments: vec![],
             shipments_sent: 0,
+            reputation: Reputation::default(),
         }
     }
 
@@ -33,15 +39,43 @@ impl Customer {
         self.shipments_sent += 1;
     }
 
+    pub fn cancel_shipment(&mut self, shipment_id: ShipmentIdInner) {
+        self.shipments.retain(|&x| x != shipment_id);
+    }
+
+    pub fn add_rating(&mut self, rating: &Rating) {
+        self.reputation.add_rating(rating);
+    }
+
+    pub fn record_dispute(&mut self) {
+        self.reputation.record_dispute();
+    }
+
+    pub fn reputation(&self) -> ReputationSummary {
+        self.reputation.summary(self.shipments_sent)
+    }
+
     pub fn id(&self) -> Principal {
         self.id
     }
 
     pub fn name(&self) -> &str {
-        &self.name
+        &self.profile.display_name
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn update_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+    }
+
+    pub fn shipments_sent(&self) -> u32 {
+        self.shipments_sent
     }
 
     pub fn shipments(&self) -> &[ShipmentIdInner] {
         &self.shipments
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,113 @@
+#![allow(unused)]
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_REASON_LENGTH: usize = 1024;
+pub const MAX_EVIDENCE_HASH_LENGTH: usize = 128;
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType, PartialEq, Eq)]
+pub enum DisputeOutcome {
+    /// Price goes back to the customer, the carrier gets its collateral back.
+    RefundCustomer,
+    /// Escrow is settled as if the dispute never happened.
+    PayCarrier,
+    /// Price is divided, `customer_share_bps` of it is refunded to the customer.
+    Split { customer_share_bps: u16 },
+    /// Customer is refunded and `amount` of carrier collateral goes to the customer.
+    SlashCollateral { amount: u64 },
+}
+
+impl DisputeOutcome {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let DisputeOutcome::Split { customer_share_bps } = self {
+            if *customer_share_bps > 10_000 {
+                return Err(anyhow::anyhow!("share cannot exceed 10000 basis points"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Dispute {
+    opened_by: Principal,
+    reason: String,
+    evidence_hash: String,
+    opened_at: u64,
+    resolved_by: Option<Principal>,
+    outcome: Option<DisputeOutcome>,
+}
+
+impl Dispute {
+    pub fn new(
+        opened_by: Principal,
+        reason: String,
+        evidence_hash: String,
+        opened_at: u64,
+    ) -> anyhow::Result<Self> {
+        if reason.trim().is_empty() {
+            return Err(anyhow::anyhow!("dispute reason is required"));
+        }
+
+        if reason.len() > MAX_REASON_LENGTH {
+            return Err(anyhow::anyhow!("dispute reason is too long"));
+        }
+
+        if evidence_hash.len() > MAX_EVIDENCE_HASH_LENGTH {
+            return Err(anyhow::anyhow!("evidence hash is too long"));
+        }
+
+        Ok(Self {
+            opened_by,
+            reason,
+            evidence_hash,
+            opened_at,
+            resolved_by: None,
+            outcome: None,
+        })
+    }
+
+    pub fn resolve(&mut self, arbiter: Principal, outcome: DisputeOutcome) {
+        self.resolved_by = Some(arbiter);
+        self.outcome = Some(outcome);
+    }
+
+    pub fn opened_by(&self) -> Principal {
+        self.opened_by
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn evidence_hash(&self) -> &str {
+        &self.evidence_hash
+    }
+
+    pub fn outcome(&self) -> Option<&DisputeOutcome> {
+        self.outcome.as_ref()
+    }
+}
+
+/// Disputes can be opened for `window_secs` after delivery, escrow is settled
+/// only once that window has passed.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct DisputeConfig {
+    pub window_secs: u64,
+}
+
+impl Default for DisputeConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl DisputeConfig {
+    pub fn window(&self) -> u64 {
+        self.window_secs.saturating_mul(1_000_000_000)
+    }
+}
@@ -0,0 +1,159 @@
+use super::carrier::Carrier;
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ALLOWED_CARRIERS: usize = 100;
+
+/// Requirements a carrier has to meet to buy a shipment, unset fields are not checked.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct CarrierEligibility {
+    /// Average rating on the 1-5 scale, carriers without ratings are rejected.
+    pub min_average_score: Option<f64>,
+    pub min_shipments_done: Option<u32>,
+    pub min_free_collateral: Option<u64>,
+    pub allowed_carriers: Option<Vec<Principal>>,
+    /// Upper bound on shipments the carrier has bought but not delivered yet.
+    pub max_active_shipments: Option<u32>,
+}
+
+impl CarrierEligibility {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(score) = self.min_average_score {
+            if !(1.0..=5.0).contains(&score) {
+                return Err(anyhow::anyhow!("minimum score must be between 1 and 5"));
+            }
+        }
+
+        if let Some(allowed) = &self.allowed_carriers {
+            if allowed.is_empty() || allowed.len() > MAX_ALLOWED_CARRIERS {
+                return Err(anyhow::anyhow!(
+                    "allowed carriers must list between 1 and {MAX_ALLOWED_CARRIERS} principals"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check(&self, carrier: &Carrier) -> anyhow::Result<()> {
+        if let Some(allowed) = &self.allowed_carriers {
+            if !allowed.contains(&carrier.id()) {
+                return Err(anyhow::anyhow!("carrier is not on the allow-list"));
+            }
+        }
+
+        if let Some(min_score) = self.min_average_score {
+            match carrier.reputation().average_score {
+                Some(score) if score >= min_score => {}
+                Some(score) => {
+                    return Err(anyhow::anyhow!(
+                        "carrier rating {score:.2} is below the required {min_score:.2}"
+                    ))
+                }
+                None => return Err(anyhow::anyhow!("carrier has no ratings yet")),
+            }
+        }
+
+        if let Some(min_done) = self.min_shipments_done {
+            if carrier.shipments_done() < min_done {
+                return Err(anyhow::anyhow!(
+                    "carrier has delivered {} shipments, {min_done} required",
+                    carrier.shipments_done()
+                ));
+            }
+        }
+
+        if let Some(min_free) = self.min_free_collateral {
+            if carrier.free_collateral() < min_free {
+                return Err(anyhow::anyhow!(
+                    "carrier has {} free collateral, {min_free} required",
+                    carrier.free_collateral()
+                ));
+            }
+        }
+
+        if let Some(max_active) = self.max_active_shipments {
+            if carrier.shipments().len() >= max_active as usize {
+                return Err(anyhow::anyhow!(
+                    "carrier already has {max_active} active shipments"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{carrier, principal};
+
+    #[test]
+    fn test_unset_requirements_accept_anyone() {
+        assert!(CarrierEligibility::default().check(&carrier(2)).is_ok());
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let eligibility = CarrierEligibility {
+            allowed_carriers: Some(vec![principal(2)]),
+            ..Default::default()
+        };
+
+        assert!(eligibility.check(&carrier(2)).is_ok());
+        assert!(eligibility.check(&carrier(3)).is_err());
+    }
+
+    #[test]
+    fn test_track_record_and_collateral() {
+        let eligibility = CarrierEligibility {
+            min_shipments_done: Some(1),
+            min_free_collateral: Some(500),
+            max_active_shipments: Some(1),
+            ..Default::default()
+        };
+        let mut carrier = carrier(2);
+        assert!(eligibility.check(&carrier).is_err());
+
+        carrier.add_shipment(1);
+        carrier.finalize_shipment(1, true);
+        carrier.deposit_collateral(500).unwrap();
+        assert!(eligibility.check(&carrier).is_ok());
+
+        carrier.add_shipment(2);
+        assert_eq!(
+            eligibility.check(&carrier).unwrap_err().to_string(),
+            "carrier already has 1 active shipments"
+        );
+    }
+
+    #[test]
+    fn test_carriers_without_ratings_miss_a_minimum_score() {
+        let eligibility = CarrierEligibility {
+            min_average_score: Some(4.0),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            eligibility.check(&carrier(2)).unwrap_err().to_string(),
+            "carrier has no ratings yet"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let score = |min_average_score| CarrierEligibility {
+            min_average_score: Some(min_average_score),
+            ..Default::default()
+        };
+        assert!(score(4.5).validate().is_ok());
+        assert!(score(6.0).validate().is_err());
+
+        let allowed = CarrierEligibility {
+            allowed_carriers: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(allowed.validate().is_err());
+    }
+}
@@ -0,0 +1,39 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Durations are in seconds, `None` disables the corresponding expiry.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct ExpiryConfig {
+    /// How long a shipment may stay unbought on the market.
+    pub pending_ttl_secs: Option<u64>,
+    /// How long a carrier has to pick up a shipment after buying it.
+    pub pickup_timeout_secs: Option<u64>,
+    /// Share of the shipment value taken from a carrier that misses the pickup timeout.
+    pub abandonment_penalty_bps: u16,
+}
+
+impl ExpiryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.abandonment_penalty_bps > 10_000 {
+            return Err(anyhow::anyhow!("penalty cannot exceed 10000 basis points"));
+        }
+
+        Ok(())
+    }
+
+    pub fn pending_ttl(&self) -> Option<u64> {
+        self.pending_ttl_secs.map(secs_to_nanos)
+    }
+
+    pub fn pickup_timeout(&self) -> Option<u64> {
+        self.pickup_timeout_secs.map(secs_to_nanos)
+    }
+
+    pub fn abandonment_penalty(&self, value: u64) -> u64 {
+        (value as u128 * self.abandonment_penalty_bps as u128 / 10_000) as u64
+    }
+}
+
+fn secs_to_nanos(secs: u64) -> u64 {
+    secs.saturating_mul(1_000_000_000)
+}
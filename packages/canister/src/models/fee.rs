@@ -0,0 +1,57 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Platform fee taken from the price paid out to the carrier at settlement.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct FeeConfig {
+    pub fee_bps: u16,
+    pub flat_fee: u64,
+}
+
+impl FeeConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.fee_bps > 10_000 {
+            return Err(anyhow::anyhow!("fee cannot exceed 10000 basis points"));
+        }
+
+        Ok(())
+    }
+
+    /// Fee charged on `earned`, never more than `earned` itself.
+    pub fn fee(&self, earned: u64) -> u64 {
+        let relative = (earned as u128 * self.fee_bps as u128 / 10_000) as u64;
+
+        relative.saturating_add(self.flat_fee).min(earned)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct FeeLedger {
+    accrued: u64,
+    withdrawn: u64,
+}
+
+impl FeeLedger {
+    pub fn accrue(&mut self, amount: u64) {
+        self.accrued = self.accrued.saturating_add(amount);
+    }
+
+    pub fn withdraw(&mut self, amount: u64) -> anyhow::Result<()> {
+        if amount > self.available() {
+            return Err(anyhow::anyhow!("not enough fees available"));
+        }
+
+        self.withdrawn += amount;
+
+        Ok(())
+    }
+
+    /// Gives back a withdrawal that was not sent.
+    pub fn restore(&mut self, amount: u64) {
+        self.withdrawn = self.withdrawn.saturating_sub(amount);
+    }
+
+    pub fn available(&self) -> u64 {
+        self.accrued - self.withdrawn
+    }
+}
@@ -0,0 +1,148 @@
+use super::{shipment::ShipmentLocation, tracking::LocationPoint};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// When enabled, carriers have to be within the radius of the source to pick a
+/// shipment up and of the destination to deliver it. Otherwise the customer
+/// has to confirm the action itself.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct GeofenceConfig {
+    pub enabled: bool,
+    pub pickup_radius_m: f64,
+    pub delivery_radius_m: f64,
+}
+
+impl GeofenceConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for radius in [self.pickup_radius_m, self.delivery_radius_m] {
+            if !radius.is_finite() || radius < 0.0 {
+                return Err(anyhow::anyhow!("invalid geofence radius"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn check_pickup(
+        &self,
+        source: &ShipmentLocation,
+        position: Option<&LocationPoint>,
+    ) -> anyhow::Result<()> {
+        self.check(source, position, self.pickup_radius_m)
+            .map_err(|e| anyhow::anyhow!("{e}, the customer has to confirm the pickup"))
+    }
+
+    pub fn check_delivery(
+        &self,
+        destination: &ShipmentLocation,
+        position: Option<&LocationPoint>,
+    ) -> anyhow::Result<()> {
+        self.check(destination, position, self.delivery_radius_m)
+            .map_err(|e| anyhow::anyhow!("{e}, the customer has to confirm the delivery"))
+    }
+
+    /// Positions count as inside when their reported point is within the fence.
+    /// Readings less accurate than the fence is wide cannot prove that.
+    fn check(
+        &self,
+        target: &ShipmentLocation,
+        position: Option<&LocationPoint>,
+        radius_m: f64,
+    ) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+
+        let position = position.ok_or(anyhow::anyhow!("position is required"))?;
+        position.validate()?;
+
+        if position.accuracy_m > radius_m {
+            return Err(anyhow::anyhow!("position is not accurate enough"));
+        }
+
+        let distance_m = position.distance_km(target) * 1000.0;
+        if distance_m > radius_m {
+            return Err(anyhow::anyhow!("position is outside the geofence"));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> GeofenceConfig {
+        GeofenceConfig {
+            enabled: true,
+            pickup_radius_m: 100.0,
+            delivery_radius_m: 200.0,
+        }
+    }
+
+    fn target() -> ShipmentLocation {
+        ShipmentLocation::new("street".to_string(), 52.2297, 21.0122)
+    }
+
+    /// About 111 metres north of the target per 0.001 degrees.
+    fn position(lat_offset: f64, accuracy_m: f64) -> LocationPoint {
+        LocationPoint {
+            lat: 52.2297 + lat_offset,
+            lng: 21.0122,
+            accuracy_m,
+            reported_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_position_inside_the_fence() {
+        let config = config();
+
+        assert!(config
+            .check_pickup(&target(), Some(&position(0.0005, 10.0)))
+            .is_ok());
+        assert!(config
+            .check_delivery(&target(), Some(&position(0.0015, 10.0)))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_position_outside_the_fence() {
+        let config = config();
+
+        assert!(config
+            .check_pickup(&target(), Some(&position(0.0015, 10.0)))
+            .is_err());
+        assert!(config.check_pickup(&target(), None).is_err());
+    }
+
+    #[test]
+    fn test_inaccurate_positions_are_rejected() {
+        let config = config();
+
+        // Accuracy never widens the fence.
+        assert!(config
+            .check_pickup(&target(), Some(&position(0.0015, 90.0)))
+            .is_err());
+        assert!(config
+            .check_pickup(&target(), Some(&position(0.0, 150.0)))
+            .is_err());
+        assert!(config
+            .check_pickup(&target(), Some(&position(1.0, f64::INFINITY)))
+            .is_err());
+        assert!(config
+            .check_pickup(&target(), Some(&position(0.0, f64::NAN)))
+            .is_err());
+    }
+
+    #[test]
+    fn test_disabled_geofence_accepts_anything() {
+        let config = GeofenceConfig::default();
+
+        assert!(config.check_pickup(&target(), None).is_ok());
+        assert!(config
+            .check_delivery(&target(), Some(&position(1.0, f64::INFINITY)))
+            .is_ok());
+    }
+}
@@ -0,0 +1,187 @@
+#![allow(unused)]
+
+use super::{settlement::Settlement, shipment::ShipmentLocation};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_HANDOFF_POINTS: usize = 8;
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType, PartialEq, Eq)]
+pub enum LegStatus {
+    Open,
+    Bought,
+    InTransit,
+    /// Handed over to the next carrier, or delivered if it was the last leg.
+    Completed,
+}
+
+/// What the marketplace shows of a leg: the route and price, not who carries it.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct LegListing {
+    pub from: ShipmentLocation,
+    pub to: ShipmentLocation,
+    pub price: u64,
+    pub status: LegStatus,
+}
+
+/// Part of a route between two hand-off points, carried by a single carrier.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Leg {
+    from: ShipmentLocation,
+    to: ShipmentLocation,
+    /// Share of the shipment price escrowed for this leg.
+    price: u64,
+    carrier: Option<Principal>,
+    status: LegStatus,
+    bought_at: Option<u64>,
+    completed_at: Option<u64>,
+    settlement: Option<Settlement>,
+}
+
+impl Leg {
+    /// Splits the route through `handoff_points` into legs and divides `price`
+    /// between them by distance. Rounding leftovers go to the last leg.
+    pub fn plan(
+        source: &ShipmentLocation,
+        handoff_points: &[ShipmentLocation],
+        destination: &ShipmentLocation,
+        price: u64,
+    ) -> Vec<Leg> {
+        if handoff_points.is_empty() {
+            return vec![];
+        }
+
+        let stops: Vec<&ShipmentLocation> = std::iter::once(source)
+            .chain(handoff_points)
+            .chain(std::iter::once(destination))
+            .collect();
+
+        let distances: Vec<f64> = stops
+            .windows(2)
+            .map(|pair| pair[0].distance_km(pair[1]))
+            .collect();
+        let total: f64 = distances.iter().sum();
+
+        let mut remaining = price;
+        let mut legs: Vec<Leg> = stops
+            .windows(2)
+            .zip(&distances)
+            .map(|(pair, distance)| {
+                let share = match total > 0.0 {
+                    true => (price as f64 * distance / total) as u64,
+                    false => price / distances.len() as u64,
+                };
+                let share = share.min(remaining);
+                remaining -= share;
+
+                Leg {
+                    from: pair[0].clone(),
+                    to: pair[1].clone(),
+                    price: share,
+                    carrier: None,
+                    status: LegStatus::Open,
+                    bought_at: None,
+                    completed_at: None,
+                    settlement: None,
+                }
+            })
+            .collect();
+
+        if let Some(last) = legs.last_mut() {
+            last.price += remaining;
+        }
+
+        legs
+    }
+
+    pub fn assign(&mut self, carrier: Principal, now: u64) {
+        self.carrier = Some(carrier);
+        self.status = LegStatus::Bought;
+        self.bought_at = Some(now);
+    }
+
+    pub fn unassign(&mut self) {
+        self.carrier = None;
+        self.status = LegStatus::Open;
+        self.bought_at = None;
+    }
+
+    pub fn start(&mut self) {
+        self.status = LegStatus::InTransit;
+    }
+
+    pub fn complete(&mut self, settlement: Option<Settlement>, now: u64) {
+        self.status = LegStatus::Completed;
+        self.completed_at = Some(now);
+        self.settlement = settlement;
+    }
+
+    pub fn from(&self) -> &ShipmentLocation {
+        &self.from
+    }
+
+    pub fn to(&self) -> &ShipmentLocation {
+        &self.to
+    }
+
+    pub fn price(&self) -> u64 {
+        self.price
+    }
+
+    pub fn carrier(&self) -> Option<Principal> {
+        self.carrier
+    }
+
+    pub fn status(&self) -> &LegStatus {
+        &self.status
+    }
+
+    pub fn settlement(&self) -> Option<&Settlement> {
+        self.settlement.as_ref()
+    }
+
+    pub fn listing(&self) -> LegListing {
+        LegListing {
+            from: self.from.clone(),
+            to: self.to.clone(),
+            price: self.price,
+            status: self.status.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(lat: f64) -> ShipmentLocation {
+        ShipmentLocation::new("stop".to_string(), lat, 21.0)
+    }
+
+    #[test]
+    fn test_direct_shipments_have_no_legs() {
+        assert!(Leg::plan(&stop(52.0), &[], &stop(50.0), 1_000).is_empty());
+    }
+
+    #[test]
+    fn test_price_is_split_by_distance() {
+        // The hand-off point is three quarters of the way.
+        let legs = Leg::plan(&stop(52.0), &[stop(50.5)], &stop(50.0), 1_000);
+
+        assert_eq!(legs.len(), 2);
+        assert_eq!(legs[0].from().lat(), 52.0);
+        assert_eq!(legs[1].to().lat(), 50.0);
+        assert!((745..=755).contains(&legs[0].price()));
+        assert_eq!(legs[0].price() + legs[1].price(), 1_000);
+        assert!(legs.iter().all(|leg| *leg.status() == LegStatus::Open));
+    }
+
+    #[test]
+    fn test_rounding_leftovers_go_to_the_last_leg() {
+        // No distance to divide by, so the price is split evenly.
+        let legs = Leg::plan(&stop(52.0), &[stop(52.0), stop(52.0)], &stop(52.0), 100);
+
+        let prices: Vec<u64> = legs.iter().map(Leg::price).collect();
+        assert_eq!(prices, vec![33, 33, 34]);
+    }
+}
@@ -1,4 +1,22 @@
 pub mod shipment;
 pub mod customer;
 pub mod shipment_id;
-pub mod carrier;
\ No newline at end of file
+pub mod carrier;
+pub mod sla;
+pub mod expiry;
+pub mod dispute;
+pub mod settlement;
+pub mod reputation;
+pub mod eligibility;
+pub mod profile;
+pub mod vehicle;
+pub mod size;
+pub mod pricing;
+pub mod fee;
+pub mod leg;
+pub mod tracking;
+pub mod proof;
+pub mod geofence;
+pub mod quota;
+pub mod mode;
+pub mod collateral;
\ No newline at end of file
@@ -0,0 +1,34 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Kill switch for incidents, set by admins.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, CandidType, PartialEq, Eq)]
+pub enum OperationalMode {
+    #[default]
+    Running,
+    /// Only queries are served, every update but switching the mode fails.
+    ReadOnly,
+    /// No new shipments are listed or bought, those under way can still be
+    /// delivered, disputed and settled.
+    MarketplacePaused,
+}
+
+impl OperationalMode {
+    pub fn check_write(&self) -> anyhow::Result<()> {
+        if *self == OperationalMode::ReadOnly {
+            return Err(anyhow::anyhow!("Canister is read-only for maintenance"));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_marketplace(&self) -> anyhow::Result<()> {
+        self.check_write()?;
+
+        if *self == OperationalMode::MarketplacePaused {
+            return Err(anyhow::anyhow!("Marketplace is paused"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use super::{shipment::ShipmentInfo, size::SizeClass};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Rates for one size class, all amounts are in the same unit as `ShipmentInfo::price`.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct RateTable {
+    pub size_class: SizeClass,
+    pub base_fee: u64,
+    pub per_km: u64,
+    /// Charged per started kilogram of chargeable weight.
+    pub per_kg: u64,
+    /// Share of the declared value charged for insurance, in basis points.
+    pub insurance_bps: u16,
+}
+
+/// Surcharges are added on top of the transport cost, in basis points.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct HandlingSurcharges {
+    pub fragile_bps: u16,
+    pub hazardous_bps: u16,
+    pub perishable_bps: u16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct PricingConfig {
+    pub rates: Vec<RateTable>,
+    pub surcharges: HandlingSurcharges,
+    /// Reject new shipments priced below their quote.
+    pub enforce_minimum_price: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Quote {
+    pub distance_km: f64,
+    pub chargeable_weight_grams: u64,
+    pub transport: u64,
+    pub surcharges: u64,
+    pub insurance: u64,
+    pub total: u64,
+}
+
+impl PricingConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (i, rate) in self.rates.iter().enumerate() {
+            if self.rates[..i]
+                .iter()
+                .any(|other| other.size_class == rate.size_class)
+            {
+                return Err(anyhow::anyhow!(
+                    "duplicate rate table for {:?}",
+                    rate.size_class
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn quote(&self, info: &ShipmentInfo) -> anyhow::Result<Quote> {
+        let size_class = info.size_category().class();
+        let rate = self
+            .rates
+            .iter()
+            .find(|rate| rate.size_class == size_class)
+            .ok_or(anyhow::anyhow!("no rates configured for {size_class:?}"))?;
+
+        let distance_km = info.source().distance_km(info.destination());
+        let chargeable_weight_grams = info.chargeable_weight_grams();
+        let started_kgs = chargeable_weight_grams.div_ceil(1000);
+
+        let transport = rate
+            .base_fee
+            .saturating_add((distance_km.ceil() as u64).saturating_mul(rate.per_km))
+            .saturating_add(started_kgs.saturating_mul(rate.per_kg));
+
+        let handling = info.handling();
+        let surcharge_bps = [
+            (handling.fragile, self.surcharges.fragile_bps),
+            (handling.hazardous, self.surcharges.hazardous_bps),
+            (handling.perishable, self.surcharges.perishable_bps),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| *flag)
+        .map(|(_, bps)| bps as u64)
+        .sum();
+
+        let surcharges = bps_of(transport, surcharge_bps);
+        let insurance = bps_of(info.value(), rate.insurance_bps as u64);
+
+        Ok(Quote {
+            distance_km,
+            chargeable_weight_grams,
+            transport,
+            surcharges,
+            insurance,
+            total: transport
+                .saturating_add(surcharges)
+                .saturating_add(insurance),
+        })
+    }
+}
+
+fn bps_of(amount: u64, bps: u64) -> u64 {
+    (amount as u128 * bps as u128 / 10_000).min(u64::MAX as u128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        shipment::ShipmentLocation,
+        size::{Handling, SizeCategory},
+    };
+
+    fn config() -> PricingConfig {
+        PricingConfig {
+            rates: vec![RateTable {
+                size_class: SizeClass::Parcel,
+                base_fee: 500,
+                per_km: 2,
+                per_kg: 100,
+                insurance_bps: 100,
+            }],
+            surcharges: HandlingSurcharges {
+                fragile_bps: 1_000,
+                hazardous_bps: 2_000,
+                perishable_bps: 0,
+            },
+            enforce_minimum_price: false,
+        }
+    }
+
+    fn parcel(side: u64, weight_grams: u64) -> ShipmentInfo {
+        ShipmentInfo::new(
+            10_000,
+            1_000,
+            ShipmentLocation::new("source".to_string(), 52.2297, 21.0122),
+            ShipmentLocation::new("destination".to_string(), 50.0647, 19.945),
+            SizeCategory::Parcel {
+                max_width: side,
+                max_height: side,
+                max_depth: side,
+            },
+        )
+        .with_weight(weight_grams)
+    }
+
+    #[test]
+    fn test_quote_adds_up_transport_surcharges_and_insurance() {
+        let info = parcel(10, 2_500).with_handling(Handling {
+            fragile: true,
+            ..Default::default()
+        });
+        let quote = config().quote(&info).unwrap();
+
+        // Started kilograms are charged in full.
+        let transport = 500 + quote.distance_km.ceil() as u64 * 2 + 3 * 100;
+        assert!((250.0..260.0).contains(&quote.distance_km));
+        assert_eq!(quote.chargeable_weight_grams, 2_500);
+        assert_eq!(quote.transport, transport);
+        assert_eq!(quote.surcharges, transport / 10);
+        assert_eq!(quote.insurance, 100);
+        assert_eq!(quote.total, transport + transport / 10 + 100);
+    }
+
+    #[test]
+    fn test_bulky_parcels_are_charged_by_volume() {
+        let quote = config().quote(&parcel(50, 100)).unwrap();
+
+        assert_eq!(quote.chargeable_weight_grams, 25_000);
+        assert_eq!(
+            quote.transport,
+            500 + quote.distance_km.ceil() as u64 * 2 + 25 * 100
+        );
+        assert_eq!(quote.surcharges, 0);
+    }
+
+    #[test]
+    fn test_size_class_without_rates_cannot_be_quoted() {
+        let mut config = config();
+        config.rates[0].size_class = SizeClass::Pallet;
+
+        assert_eq!(
+            config.quote(&parcel(10, 0)).unwrap_err().to_string(),
+            "no rates configured for Parcel"
+        );
+    }
+
+    #[test]
+    fn test_rate_tables_are_unique_per_size_class() {
+        let mut config = config();
+        assert!(config.validate().is_ok());
+
+        config.rates.push(config.rates[0].clone());
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+pub const MAX_CONTACT_HASH_LENGTH: usize = 128;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, CandidType, PartialEq, Eq)]
+pub enum VehicleClass {
+    Bicycle,
+    Motorcycle,
+    Car,
+    Van,
+    Truck,
+}
+
+/// Circle a carrier is willing to operate in.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct ServiceArea {
+    pub lat: f64,
+    pub lng: f64,
+    pub radius_km: f64,
+}
+
+/// Contact details are never stored in plain text, only their hash so that
+/// the off-chain services can match them.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Profile {
+    pub display_name: String,
+    pub contact_hash: Option<String>,
+    pub vehicle_class: Option<VehicleClass>,
+    pub service_area: Option<ServiceArea>,
+}
+
+impl Profile {
+    pub fn new(display_name: String) -> Self {
+        Self {
+            display_name,
+            contact_hash: None,
+            vehicle_class: None,
+            service_area: None,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let name = self.display_name.trim();
+        if name.is_empty() || name.len() > MAX_DISPLAY_NAME_LENGTH {
+            return Err(anyhow::anyhow!(
+                "display name must have between 1 and {MAX_DISPLAY_NAME_LENGTH} characters"
+            ));
+        }
+
+        if let Some(hash) = &self.contact_hash {
+            if hash.len() > MAX_CONTACT_HASH_LENGTH {
+                return Err(anyhow::anyhow!("contact hash is too long"));
+            }
+        }
+
+        if let Some(area) = &self.service_area {
+            if !(-90.0..=90.0).contains(&area.lat)
+                || !(-180.0..=180.0).contains(&area.lng)
+                || area.radius_km.is_nan()
+                || area.radius_km <= 0.0
+            {
+                return Err(anyhow::anyhow!("invalid service area"));
+            }
+        }
+
+        Ok(())
+    }
+}
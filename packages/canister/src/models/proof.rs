@@ -0,0 +1,125 @@
+use super::{shipment::ShipmentLocation, tracking::LocationPoint};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ARTIFACT_HASH_LENGTH: usize = 128;
+/// How far from the destination a delivery position may be, on top of its accuracy.
+pub const DELIVERY_TOLERANCE_KM: f64 = 0.5;
+/// Least accurate position accepted as proof, in metres.
+pub const MAX_POSITION_ACCURACY_M: f64 = 100.0;
+
+/// Evidence a carrier attaches when finalizing. Photos and signatures stay
+/// off-chain, only their content hashes are stored.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct ProofOfDelivery {
+    pub photo_hash: Option<String>,
+    pub signature_hash: Option<String>,
+    pub position: Option<LocationPoint>,
+    pub captured_at: u64,
+}
+
+impl ProofOfDelivery {
+    pub fn validate(&self, destination: &ShipmentLocation, now: u64) -> anyhow::Result<()> {
+        if self.photo_hash.is_none() && self.signature_hash.is_none() && self.position.is_none() {
+            return Err(anyhow::anyhow!("proof of delivery is empty"));
+        }
+
+        for hash in [&self.photo_hash, &self.signature_hash]
+            .into_iter()
+            .flatten()
+        {
+            if hash.is_empty() || hash.len() > MAX_ARTIFACT_HASH_LENGTH {
+                return Err(anyhow::anyhow!("invalid artifact hash"));
+            }
+        }
+
+        if self.captured_at > now {
+            return Err(anyhow::anyhow!("proof of delivery is from the future"));
+        }
+
+        if let Some(position) = &self.position {
+            position.validate()?;
+
+            if position.accuracy_m > MAX_POSITION_ACCURACY_M {
+                return Err(anyhow::anyhow!("delivery position is not accurate enough"));
+            }
+
+            let slack_km = DELIVERY_TOLERANCE_KM + position.accuracy_m / 1000.0;
+            if position.distance_km(destination) > slack_km {
+                return Err(anyhow::anyhow!(
+                    "delivery position is too far from the destination"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_000;
+
+    fn destination() -> ShipmentLocation {
+        ShipmentLocation::new("street".to_string(), 52.2297, 21.0122)
+    }
+
+    fn proof(lat_offset: f64, accuracy_m: f64) -> ProofOfDelivery {
+        ProofOfDelivery {
+            photo_hash: None,
+            signature_hash: None,
+            position: Some(LocationPoint {
+                lat: 52.2297 + lat_offset,
+                lng: 21.0122,
+                accuracy_m,
+                reported_at: NOW,
+            }),
+            captured_at: NOW,
+        }
+    }
+
+    #[test]
+    fn test_position_near_the_destination() {
+        assert!(proof(0.004, 10.0).validate(&destination(), NOW).is_ok());
+        assert!(proof(0.01, 10.0).validate(&destination(), NOW).is_err());
+    }
+
+    #[test]
+    fn test_inaccurate_positions_are_rejected() {
+        let far = 1.0;
+
+        assert!(proof(far, f64::INFINITY)
+            .validate(&destination(), NOW)
+            .is_err());
+        assert!(proof(far, 1_000_000.0)
+            .validate(&destination(), NOW)
+            .is_err());
+        assert!(proof(0.0, MAX_POSITION_ACCURACY_M + 1.0)
+            .validate(&destination(), NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn test_artifacts_and_timestamps() {
+        let empty = ProofOfDelivery {
+            position: None,
+            ..proof(0.0, 0.0)
+        };
+        assert!(empty.validate(&destination(), NOW).is_err());
+
+        let photo = ProofOfDelivery {
+            photo_hash: Some("hash".to_string()),
+            ..empty.clone()
+        };
+        assert!(photo.validate(&destination(), NOW).is_ok());
+        assert!(photo.validate(&destination(), NOW - 1).is_err());
+
+        let oversized = ProofOfDelivery {
+            photo_hash: Some("h".repeat(MAX_ARTIFACT_HASH_LENGTH + 1)),
+            ..empty
+        };
+        assert!(oversized.validate(&destination(), NOW).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+/// Limits per principal, `None` disables the corresponding limit.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct QuotaConfig {
+    pub shipments_per_hour: Option<u32>,
+    /// Shipments a customer may have waiting for a carrier at once.
+    pub max_pending_shipments: Option<u32>,
+    /// Failed finalize attempts per hour before the caller is locked out.
+    pub failed_finalizes_per_hour: Option<u32>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            shipments_per_hour: Some(20),
+            max_pending_shipments: Some(50),
+            failed_finalizes_per_hour: Some(5),
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if [
+            self.shipments_per_hour,
+            self.max_pending_shipments,
+            self.failed_finalizes_per_hour,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(anyhow::anyhow!(
+                "limits must be positive, use null to disable one"
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Recent activity of a principal counted against its quotas.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct Usage {
+    created: VecDeque<u64>,
+    failed_finalizes: VecDeque<u64>,
+}
+
+impl Usage {
+    pub fn check_create(
+        &mut self,
+        config: &QuotaConfig,
+        pending: usize,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        self.prune(now);
+
+        if exceeded(config.shipments_per_hour, self.created.len()) {
+            return Err(anyhow::anyhow!(
+                "Too many shipments created in the last hour"
+            ));
+        }
+
+        if exceeded(config.max_pending_shipments, pending) {
+            return Err(anyhow::anyhow!("Too many pending shipments"));
+        }
+
+        Ok(())
+    }
+
+    pub fn record_create(&mut self, now: u64) {
+        self.created.push_back(now);
+    }
+
+    pub fn check_finalize(&mut self, config: &QuotaConfig, now: u64) -> anyhow::Result<()> {
+        self.prune(now);
+
+        if exceeded(
+            config.failed_finalizes_per_hour,
+            self.failed_finalizes.len(),
+        ) {
+            return Err(anyhow::anyhow!(
+                "Too many failed finalize attempts, try again later"
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn record_failed_finalize(&mut self, now: u64) {
+        self.failed_finalizes.push_back(now);
+    }
+
+    /// Drops activity older than an hour, returning whether anything is left.
+    pub fn prune(&mut self, now: u64) -> bool {
+        for entries in [&mut self.created, &mut self.failed_finalizes] {
+            while entries
+                .front()
+                .is_some_and(|at| now >= at.saturating_add(HOUR))
+            {
+                entries.pop_front();
+            }
+        }
+
+        !self.created.is_empty() || !self.failed_finalizes.is_empty()
+    }
+}
+
+fn exceeded(limit: Option<u32>, count: usize) -> bool {
+    limit.is_some_and(|limit| count >= limit as usize)
+}
@@ -0,0 +1,126 @@
+#![allow(unused)]
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const MAX_TAGS: usize = 5;
+pub const MAX_TAG_LENGTH: usize = 32;
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Rating {
+    score: u8,
+    tags: Vec<String>,
+    rated_by: Principal,
+    rated_at: u64,
+}
+
+impl Rating {
+    pub fn new(
+        score: u8,
+        tags: Vec<String>,
+        rated_by: Principal,
+        rated_at: u64,
+    ) -> anyhow::Result<Self> {
+        if !(1..=5).contains(&score) {
+            return Err(anyhow::anyhow!("score must be between 1 and 5"));
+        }
+
+        if tags.len() > MAX_TAGS {
+            return Err(anyhow::anyhow!("too many tags"));
+        }
+
+        if tags
+            .iter()
+            .any(|tag| tag.is_empty() || tag.len() > MAX_TAG_LENGTH)
+        {
+            return Err(anyhow::anyhow!("invalid tag"));
+        }
+
+        Ok(Self {
+            score,
+            tags,
+            rated_by,
+            rated_at,
+        })
+    }
+
+    pub fn score(&self) -> u8 {
+        self.score
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn rated_by(&self) -> Principal {
+        self.rated_by
+    }
+}
+
+/// Running totals kept on carriers and customers, rates are derived on read.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct Reputation {
+    ratings: u32,
+    score_total: u64,
+    tags: BTreeMap<String, u32>,
+    on_time: u32,
+    late: u32,
+    disputes: u32,
+}
+
+impl Reputation {
+    pub fn add_rating(&mut self, rating: &Rating) {
+        self.ratings += 1;
+        self.score_total += rating.score as u64;
+
+        for tag in &rating.tags {
+            *self.tags.entry(tag.clone()).or_default() += 1;
+        }
+    }
+
+    pub fn record_delivery(&mut self, on_time: bool) {
+        match on_time {
+            true => self.on_time += 1,
+            false => self.late += 1,
+        }
+    }
+
+    pub fn record_dispute(&mut self) {
+        self.disputes += 1;
+    }
+
+    pub fn average_score(&self) -> Option<f64> {
+        (self.ratings > 0).then(|| self.score_total as f64 / self.ratings as f64)
+    }
+
+    pub fn on_time_rate(&self) -> Option<f64> {
+        let deliveries = self.on_time + self.late;
+        (deliveries > 0).then(|| self.on_time as f64 / deliveries as f64)
+    }
+
+    pub fn summary(&self, shipments: u32) -> ReputationSummary {
+        ReputationSummary {
+            shipments,
+            ratings: self.ratings,
+            average_score: self.average_score(),
+            on_time_rate: self.on_time_rate(),
+            dispute_rate: (shipments > 0).then(|| self.disputes as f64 / shipments as f64),
+            tags: self
+                .tags
+                .iter()
+                .map(|(tag, count)| (tag.clone(), *count))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct ReputationSummary {
+    pub shipments: u32,
+    pub ratings: u32,
+    pub average_score: Option<f64>,
+    pub on_time_rate: Option<f64>,
+    pub dispute_rate: Option<f64>,
+    pub tags: Vec<(String, u32)>,
+}
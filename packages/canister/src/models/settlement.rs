@@ -0,0 +1,13 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// How the escrow of a shipment was paid out once it was closed.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType, PartialEq, Eq)]
+pub struct Settlement {
+    /// Price earned minus the platform fee. The carrier's collateral is not part
+    /// of it, it goes back to the carrier's free collateral instead.
+    pub carrier_payout: u64,
+    /// Price refunded plus penalties collected for the customer.
+    pub customer_refund: u64,
+    pub platform_fee: u64,
+}
@@ -1,21 +1,31 @@
 #![allow(unused)]
 
-use super::{carrier::Carrier, customer::Customer, shipment_id::ShipmentIdInner};
+use super::{
+    carrier::Carrier,
+    customer::Customer,
+    dispute::{Dispute, DisputeOutcome},
+    eligibility::CarrierEligibility,
+    fee::FeeConfig,
+    leg::{Leg, LegListing, LegStatus, MAX_HANDOFF_POINTS},
+    proof::ProofOfDelivery,
+    reputation::Rating,
+    settlement::Settlement,
+    shipment_id::ShipmentIdInner,
+    size::{Handling, SizeCategory},
+    sla::SlaBreach,
+    vehicle::CargoLoad,
+};
+use crate::env::Environment;
 use anyhow::Context;
 use candid::{CandidType, Principal};
 use hex::FromHex;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
-#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
-pub enum SizeCategory {
-    Envelope,
-    Parcel {
-        max_width: u64,
-        max_height: u64,
-        max_depth: u64,
-    },
-}
+pub const MAX_NAME_LENGTH: usize = 128;
+pub const MAX_STREET_LENGTH: usize = 256;
+/// Hex encoded SHA-256 digest.
+pub const HASHED_SECRET_LENGTH: usize = 64;
 
 #[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
 pub struct ShipmentLocation {
@@ -24,10 +34,67 @@ pub struct ShipmentLocation {
     lng: f64,
 }
 
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
 impl ShipmentLocation {
     pub fn new(street: String, lat: f64, lng: f64) -> Self {
         Self { street, lat, lng }
     }
+
+    pub fn lat(&self) -> f64 {
+        self.lat
+    }
+
+    pub fn lng(&self) -> f64 {
+        self.lng
+    }
+
+    /// Ranges reject NaN as well, so valid coordinates are always finite.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.street.len() > MAX_STREET_LENGTH {
+            return Err(anyhow::anyhow!("street is too long"));
+        }
+
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lng) {
+            return Err(anyhow::anyhow!("invalid coordinates"));
+        }
+
+        Ok(())
+    }
+
+    /// Great-circle distance using the haversine formula.
+    pub fn distance_km(&self, other: &ShipmentLocation) -> f64 {
+        let d_lat = (other.lat - self.lat).to_radians();
+        let d_lng = (other.lng - self.lng).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2)
+            + self.lat.to_radians().cos()
+                * other.lat.to_radians().cos()
+                * (d_lng / 2.0).sin().powi(2);
+
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// Time range in nanoseconds since the epoch, same clock as `created_at`.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct TimeWindow {
+    start: u64,
+    end: u64,
+}
+
+impl TimeWindow {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self { start, end }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
@@ -37,6 +104,14 @@ pub struct ShipmentInfo {
     source: ShipmentLocation,
     destination: ShipmentLocation,
     size_category: SizeCategory,
+    /// Total weight, multi-package shipments declare it per package instead.
+    weight_grams: u64,
+    handling: Handling,
+    pickup_window: Option<TimeWindow>,
+    delivery_window: Option<TimeWindow>,
+    eligibility: Option<CarrierEligibility>,
+    /// Intermediate stops where the shipment changes carriers, in route order.
+    handoff_points: Vec<ShipmentLocation>,
 }
 
 impl ShipmentInfo {
@@ -61,15 +136,182 @@ impl ShipmentInfo {
             source,
             destination,
             size_category,
+            weight_grams: 0,
+            handling: Handling::default(),
+            pickup_window: None,
+            delivery_window: None,
+            eligibility: None,
+            handoff_points: vec![],
+        }
+    }
+
+    pub fn with_weight(mut self, weight_grams: u64) -> Self {
+        self.weight_grams = weight_grams;
+        self
+    }
+
+    pub fn with_handling(mut self, handling: Handling) -> Self {
+        self.handling = handling;
+        self
+    }
+
+    pub fn size_category(&self) -> &SizeCategory {
+        &self.size_category
+    }
+
+    pub fn handling(&self) -> Handling {
+        self.handling
+    }
+
+    pub fn source(&self) -> &ShipmentLocation {
+        &self.source
+    }
+
+    pub fn destination(&self) -> &ShipmentLocation {
+        &self.destination
+    }
+
+    pub fn weight_grams(&self) -> u64 {
+        self.size_category
+            .package_weight_grams()
+            .unwrap_or(self.weight_grams)
+    }
+
+    /// The greater of actual and volumetric weight. Pricing should use this
+    /// rather than either weight on its own.
+    pub fn chargeable_weight_grams(&self) -> u64 {
+        self.weight_grams()
+            .max(self.size_category.volumetric_weight_grams())
+    }
+
+    /// Space and weight the shipment takes in a vehicle. Volume is tracked on
+    /// its own here, so the load is the actual weight, not the chargeable one
+    /// which would count bulky items twice.
+    pub fn cargo_load(&self) -> CargoLoad {
+        CargoLoad {
+            volume: self.size_category.volume(),
+            weight_grams: self.weight_grams(),
         }
     }
+
+    pub fn with_time_windows(
+        mut self,
+        pickup_window: Option<TimeWindow>,
+        delivery_window: Option<TimeWindow>,
+    ) -> Self {
+        self.pickup_window = pickup_window;
+        self.delivery_window = delivery_window;
+        self
+    }
+
+    pub fn with_eligibility(mut self, eligibility: Option<CarrierEligibility>) -> Self {
+        self.eligibility = eligibility;
+        self
+    }
+
+    pub fn with_handoff_points(mut self, handoff_points: Vec<ShipmentLocation>) -> Self {
+        self.handoff_points = handoff_points;
+        self
+    }
+
+    pub fn handoff_points(&self) -> &[ShipmentLocation] {
+        &self.handoff_points
+    }
+
+    pub fn eligibility(&self) -> Option<&CarrierEligibility> {
+        self.eligibility.as_ref()
+    }
+
+    pub fn pickup_window(&self) -> Option<&TimeWindow> {
+        self.pickup_window.as_ref()
+    }
+
+    pub fn delivery_window(&self) -> Option<&TimeWindow> {
+        self.delivery_window.as_ref()
+    }
+
+    pub fn validate(&self, now: u64) -> anyhow::Result<()> {
+        self.size_category.validate()?;
+
+        if self.handling.perishable && self.delivery_window.is_none() {
+            return Err(anyhow::anyhow!(
+                "perishable shipments need a delivery window"
+            ));
+        }
+
+        for window in [&self.pickup_window, &self.delivery_window]
+            .into_iter()
+            .flatten()
+        {
+            if window.start >= window.end {
+                return Err(anyhow::anyhow!("time window must end after it starts"));
+            }
+
+            if window.end <= now {
+                return Err(anyhow::anyhow!("time window is already over"));
+            }
+        }
+
+        if let (Some(pickup), Some(delivery)) = (&self.pickup_window, &self.delivery_window) {
+            if delivery.end <= pickup.start {
+                return Err(anyhow::anyhow!(
+                    "delivery window ends before pickup window starts"
+                ));
+            }
+        }
+
+        if let Some(eligibility) = &self.eligibility {
+            eligibility.validate()?;
+        }
+
+        if self.handoff_points.len() > MAX_HANDOFF_POINTS {
+            return Err(anyhow::anyhow!("too many handoff points"));
+        }
+
+        self.validate_locations()
+    }
+
+    /// The checks quotes need, since pricing goes by the distance.
+    pub fn validate_locations(&self) -> anyhow::Result<()> {
+        for location in [&self.source, &self.destination]
+            .into_iter()
+            .chain(&self.handoff_points)
+        {
+            location.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a carrier buying the shipment at `now` can still make both windows.
+    pub fn can_be_met(&self, now: u64) -> bool {
+        [&self.pickup_window, &self.delivery_window]
+            .into_iter()
+            .flatten()
+            .all(|window| window.end > now)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, CandidType, PartialEq, Eq)]
 pub enum ShipmentStatus {
     Pending,
     Bought,
+    InTransit,
     Delivered,
+    Disputed,
+    Cancelled,
+}
+
+/// What carriers browsing the marketplace see of a shipment. It leaves out the
+/// customer, the secret, the message and everything recorded after purchase.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct ShipmentListing {
+    pub id: ShipmentIdInner,
+    pub name: String,
+    pub info: ShipmentInfo,
+    pub status: ShipmentStatus,
+    pub listed_at: u64,
+    pub legs: Vec<LegListing>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
@@ -82,7 +324,28 @@ pub struct Shipment {
     message: Option<String>,
     carrier: Option<Principal>,
     customer: Principal,
+    /// Who the shipment is delivered to, allowed to read and track it.
+    recipient: Option<Principal>,
     created_at: u64,
+    listed_at: u64,
+    bought_at: Option<u64>,
+    picked_up_at: Option<u64>,
+    delivered_at: Option<u64>,
+    sla_breaches: Vec<SlaBreach>,
+    penalty: u64,
+    /// Part of the shipment value the current carrier still has locked as deposit.
+    collateral: u64,
+    proof_of_delivery: Option<ProofOfDelivery>,
+    dispute: Option<Dispute>,
+    settlement: Option<Settlement>,
+    /// Ratings the customer gave the carriers, keyed by the rated carrier.
+    carrier_ratings: Vec<(Principal, Rating)>,
+    /// Ratings the carriers gave the customer, at most one per carrier.
+    customer_ratings: Vec<Rating>,
+    /// Empty for shipments carried door to door by a single carrier. Otherwise
+    /// `carrier` and `collateral` always refer to the leg at `current_leg`.
+    legs: Vec<Leg>,
+    current_leg: u32,
 }
 
 impl Shipment {
@@ -95,17 +358,25 @@ impl Shipment {
     }
 
     pub fn create(
+        env: &impl Environment,
         creator: &mut Customer,
         id: ShipmentIdInner,
         hashed_secret: String,
         name: String,
         info: ShipmentInfo,
     ) -> Self {
-        let created_at = ic_cdk::api::time();
+        let created_at = env.now();
 
         creator.add_shipment(id);
 
-        let shipment = Self {
+        let legs = Leg::plan(
+            &info.source,
+            &info.handoff_points,
+            &info.destination,
+            info.price,
+        );
+
+        Self {
             id,
             info,
             name,
@@ -114,10 +385,40 @@ impl Shipment {
             status: ShipmentStatus::Pending,
             carrier: None,
             customer: creator.id(),
+            recipient: None,
             created_at,
-        };
+            listed_at: created_at,
+            bought_at: None,
+            picked_up_at: None,
+            delivered_at: None,
+            sla_breaches: vec![],
+            penalty: 0,
+            collateral: 0,
+            proof_of_delivery: None,
+            dispute: None,
+            settlement: None,
+            carrier_ratings: vec![],
+            customer_ratings: vec![],
+            legs,
+            current_leg: 0,
+        }
+    }
 
-        shipment
+    /// Checks what `create` takes besides the info, before anything is stored.
+    pub fn validate_listing(name: &str, hashed_secret: &str) -> anyhow::Result<()> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(anyhow::anyhow!(
+                "name must be between 1 and {MAX_NAME_LENGTH} bytes"
+            ));
+        }
+
+        if hashed_secret.len() != HASHED_SECRET_LENGTH || Vec::from_hex(hashed_secret).is_err() {
+            return Err(anyhow::anyhow!(
+                "hashed secret must be a hex encoded SHA-256 digest"
+            ));
+        }
+
+        Ok(())
     }
 
     fn validate_secret(&self, secret: Option<String>) -> anyhow::Result<()> {
@@ -129,9 +430,21 @@ impl Shipment {
         let result = hasher.finalize();
 
         if result[..] == hex {
-            return Ok(());
+            Ok(())
         } else {
-            return Err(anyhow::anyhow!("secret verification failed"));
+            Err(anyhow::anyhow!("secret verification failed"))
+        }
+    }
+
+    /// The customer confirms a delivery itself, anyone else needs the secret.
+    pub fn check_secret(
+        &self,
+        caller: Principal,
+        secret_key: Option<String>,
+    ) -> anyhow::Result<()> {
+        match caller == self.customer {
+            true => Ok(()),
+            false => self.validate_secret(secret_key),
         }
     }
 
@@ -140,34 +453,640 @@ impl Shipment {
         carrier: &mut Carrier,
         customer: &mut Customer,
         secret_key: Option<String>,
+        proof: Option<ProofOfDelivery>,
         caller: Principal,
+        now: u64,
     ) -> anyhow::Result<()> {
-        if self.status != ShipmentStatus::Bought {
+        if !matches!(
+            self.status,
+            ShipmentStatus::Bought | ShipmentStatus::InTransit
+        ) {
             return Err(anyhow::anyhow!("shipment is not ready to be finalized"));
         }
 
-        match caller == self.customer {
-            true => {}
-            false => self.validate_secret(secret_key)?,
+        if self.next_leg().is_some() {
+            return Err(anyhow::anyhow!(
+                "shipment has to be handed over to the next carrier first"
+            ));
+        }
+
+        self.check_secret(caller, secret_key)?;
+
+        if let Some(proof) = &proof {
+            proof.validate(&self.info.destination, now)?;
         }
 
+        self.deliver(carrier, customer, proof, now);
+
+        Ok(())
+    }
+
+    fn deliver(
+        &mut self,
+        carrier: &mut Carrier,
+        customer: &mut Customer,
+        proof: Option<ProofOfDelivery>,
+        now: u64,
+    ) {
         self.status = ShipmentStatus::Delivered;
+        self.delivered_at = Some(now);
+        self.proof_of_delivery = proof;
+
+        if let Some(leg) = self.legs.last_mut() {
+            leg.complete(None, now);
+        }
 
-        carrier.finalize_shipment(self.id());
+        carrier.finalize_shipment(self.id(), self.sla_breaches.is_empty());
+        carrier.unload_cargo(self.info.cargo_load());
         customer.finalize_shipment(self.id());
+    }
+
+    /// Delivered shipments are settled once nobody disputed them within `window`.
+    pub fn is_settleable(&self, window: u64, now: u64) -> bool {
+        self.status == ShipmentStatus::Delivered
+            && self.settlement.is_none()
+            && self
+                .delivered_at
+                .is_some_and(|delivered_at| now >= delivered_at.saturating_add(window))
+    }
+
+    /// Pays the carrier the price and returns its collateral, while penalties
+    /// collected on the way go to the customer.
+    pub fn settle(
+        &mut self,
+        carrier: &mut Carrier,
+        fees: &FeeConfig,
+    ) -> anyhow::Result<Settlement> {
+        if self.status != ShipmentStatus::Delivered {
+            return Err(anyhow::anyhow!("shipment is not delivered"));
+        }
+
+        self.close(carrier, 0, fees)
+    }
+
+    fn close(
+        &mut self,
+        carrier: &mut Carrier,
+        customer_share: u64,
+        fees: &FeeConfig,
+    ) -> anyhow::Result<Settlement> {
+        if self.settlement.is_some() {
+            return Err(anyhow::anyhow!("shipment is already settled"));
+        }
+
+        let price = self.escrowed_price();
+        let customer_share = customer_share.min(price);
+        let earned = price - customer_share;
+        let platform_fee = fees.fee(earned);
+        let settlement = Settlement {
+            carrier_payout: earned - platform_fee,
+            customer_refund: checked_add(customer_share, self.penalty)?,
+            platform_fee,
+        };
+
+        carrier.release_collateral(self.collateral);
+        self.collateral = 0;
+        self.settlement = Some(settlement.clone());
+
+        Ok(settlement)
+    }
+
+    pub fn open_dispute(
+        &mut self,
+        carrier: &mut Carrier,
+        customer: &mut Customer,
+        dispute: Dispute,
+        window: u64,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let caller = dispute.opened_by();
+        if caller != self.customer && Some(caller) != self.carrier {
+            return Err(anyhow::anyhow!("caller is not a party of the shipment"));
+        }
+
+        if self.status != ShipmentStatus::Delivered || self.settlement.is_some() {
+            return Err(anyhow::anyhow!("shipment cannot be disputed"));
+        }
+
+        if self.is_settleable(window, now) {
+            return Err(anyhow::anyhow!("dispute window is over"));
+        }
+
+        self.dispute = Some(dispute);
+        self.status = ShipmentStatus::Disputed;
+
+        carrier.record_dispute();
+        customer.record_dispute();
 
         Ok(())
     }
 
-    pub fn buy(&mut self, carrier: &mut Carrier) -> anyhow::Result<()> {
+    /// The customer and each carrier of a delivered shipment, including the
+    /// carriers of earlier legs, can rate each other exactly once.
+    pub fn rate(
+        &mut self,
+        carrier: &mut Carrier,
+        customer: &mut Customer,
+        rating: Rating,
+    ) -> anyhow::Result<()> {
+        if self.delivered_at.is_none() {
+            return Err(anyhow::anyhow!("shipment was not delivered"));
+        }
+
+        if !self.carriers().contains(&carrier.id()) {
+            return Err(anyhow::anyhow!("carrier did not carry the shipment"));
+        }
+
+        let rated_by = rating.rated_by();
+        if rated_by == self.customer {
+            if self
+                .carrier_ratings
+                .iter()
+                .any(|(id, _)| *id == carrier.id())
+            {
+                return Err(anyhow::anyhow!("shipment is already rated"));
+            }
+
+            carrier.add_rating(&rating);
+            self.carrier_ratings.push((carrier.id(), rating));
+        } else if rated_by == carrier.id() {
+            if self
+                .customer_ratings
+                .iter()
+                .any(|r| r.rated_by() == rated_by)
+            {
+                return Err(anyhow::anyhow!("shipment is already rated"));
+            }
+
+            customer.add_rating(&rating);
+            self.customer_ratings.push(rating);
+        } else {
+            return Err(anyhow::anyhow!("caller is not a party of the shipment"));
+        }
+
+        Ok(())
+    }
+
+    pub fn resolve_dispute(
+        &mut self,
+        carrier: &mut Carrier,
+        arbiter: Principal,
+        outcome: DisputeOutcome,
+        fees: &FeeConfig,
+    ) -> anyhow::Result<Settlement> {
+        if self.status != ShipmentStatus::Disputed {
+            return Err(anyhow::anyhow!("shipment is not disputed"));
+        }
+
+        outcome.validate()?;
+
+        let price = self.escrowed_price();
+        let (customer_share, status) = match outcome {
+            DisputeOutcome::PayCarrier => (0, ShipmentStatus::Delivered),
+            DisputeOutcome::RefundCustomer => (price, ShipmentStatus::Cancelled),
+            DisputeOutcome::Split { customer_share_bps } => (
+                (price as u128 * customer_share_bps as u128 / 10_000) as u64,
+                ShipmentStatus::Delivered,
+            ),
+            DisputeOutcome::SlashCollateral { amount } => {
+                self.apply_penalty(carrier, amount)?;
+                (price, ShipmentStatus::Cancelled)
+            }
+        };
+
+        let settlement = self.close(carrier, customer_share, fees)?;
+
+        if let Some(dispute) = self.dispute.as_mut() {
+            dispute.resolve(arbiter, outcome);
+        }
+        self.status = status;
+
+        Ok(settlement)
+    }
+
+    /// Buys a direct shipment, or the first leg still open on a multi-leg one.
+    pub fn buy(&mut self, carrier: &mut Carrier, now: u64) -> anyhow::Result<()> {
+        if !self.legs.is_empty() {
+            let leg = self
+                .legs
+                .iter()
+                .position(|leg| *leg.status() == LegStatus::Open)
+                .ok_or(anyhow::anyhow!("all legs are already bought"))?;
+
+            return self.buy_leg(carrier, leg as u32, now);
+        }
+
         if self.status != ShipmentStatus::Pending {
             return Err(anyhow::anyhow!("shipment is not pending"));
         }
 
+        self.check_carrier(carrier, now)?;
+        self.reserve(carrier)?;
+
         self.carrier = Some(carrier.id());
         self.status = ShipmentStatus::Bought;
+        self.bought_at = Some(now);
+        self.collateral = self.info.value;
 
+        Ok(())
+    }
+
+    /// Reserves one leg for `carrier`, which locks the full shipment value as
+    /// collateral since it will hold the goods for a part of the route.
+    pub fn buy_leg(&mut self, carrier: &mut Carrier, leg: u32, now: u64) -> anyhow::Result<()> {
+        if !matches!(
+            self.status,
+            ShipmentStatus::Pending | ShipmentStatus::Bought | ShipmentStatus::InTransit
+        ) {
+            return Err(anyhow::anyhow!("shipment is no longer on the market"));
+        }
+
+        if self.involves_carrier(carrier.id()) {
+            return Err(anyhow::anyhow!(
+                "carrier already holds a leg of this shipment"
+            ));
+        }
+
+        let open = self
+            .legs
+            .get(leg as usize)
+            .ok_or(anyhow::anyhow!("leg not found"))?
+            .status()
+            == &LegStatus::Open;
+        if !open {
+            return Err(anyhow::anyhow!("leg is already bought"));
+        }
+
+        self.check_carrier(carrier, now)?;
+        self.reserve(carrier)?;
+
+        self.legs[leg as usize].assign(carrier.id(), now);
+        if leg == self.current_leg {
+            self.carrier = Some(carrier.id());
+            self.status = ShipmentStatus::Bought;
+            self.bought_at = Some(now);
+            self.collateral = self.info.value;
+        }
+
+        Ok(())
+    }
+
+    fn check_carrier(&self, carrier: &Carrier, now: u64) -> anyhow::Result<()> {
+        // Would let a single principal deliver to itself without the secret
+        // and rate itself.
+        if carrier.id() == self.customer {
+            return Err(anyhow::anyhow!(
+                "customers cannot carry their own shipments"
+            ));
+        }
+
+        if !self.info.can_be_met(now) {
+            return Err(anyhow::anyhow!(
+                "shipment time windows can no longer be met"
+            ));
+        }
+
+        if let Some(eligibility) = &self.info.eligibility {
+            eligibility
+                .check(carrier)
+                .map_err(|e| anyhow::anyhow!("carrier is not eligible: {e}"))?;
+        }
+
+        carrier.check_capacity(&self.info)
+    }
+
+    fn reserve(&self, carrier: &mut Carrier) -> anyhow::Result<()> {
+        carrier.lock_collateral(self.info.value)?;
         carrier.add_shipment(self.id());
+        carrier.load_cargo(self.info.cargo_load());
+
+        Ok(())
+    }
+
+    /// The carrier of the next leg confirms it received the shipment. This
+    /// closes the leg of `previous`, paying out its share of the price and
+    /// returning its collateral, and makes the caller the active carrier.
+    pub fn confirm_handoff(
+        &mut self,
+        previous: &mut Carrier,
+        caller: Principal,
+        fees: &FeeConfig,
+        now: u64,
+    ) -> anyhow::Result<Settlement> {
+        if self.status != ShipmentStatus::InTransit {
+            return Err(anyhow::anyhow!("shipment is not in transit"));
+        }
+
+        let next = self
+            .next_leg()
+            .ok_or(anyhow::anyhow!("shipment is on its last leg"))?;
+        if next.carrier() != Some(caller) {
+            return Err(anyhow::anyhow!("caller is not the carrier of the next leg"));
+        }
+
+        if self.carrier != Some(previous.id()) {
+            return Err(anyhow::anyhow!("carrier does not hold the current leg"));
+        }
+
+        let current = self.current_leg as usize;
+        let price = self.legs[current].price();
+        let platform_fee = fees.fee(price);
+        let settlement = Settlement {
+            carrier_payout: price - platform_fee,
+            customer_refund: 0,
+            platform_fee,
+        };
+
+        previous.finalize_shipment(self.id(), self.sla_breaches.is_empty());
+        previous.unload_cargo(self.info.cargo_load());
+        previous.release_collateral(self.collateral);
+
+        self.legs[current].complete(Some(settlement.clone()), now);
+        self.legs[current + 1].start();
+        self.current_leg += 1;
+        self.carrier = Some(caller);
+        self.collateral = self.info.value;
+
+        Ok(settlement)
+    }
+
+    /// Frees a carrier that bought a later leg the shipment will never reach.
+    pub fn release_leg(&mut self, carrier: &mut Carrier) -> anyhow::Result<()> {
+        let leg = self
+            .legs
+            .iter_mut()
+            .find(|leg| leg.carrier() == Some(carrier.id()) && *leg.status() == LegStatus::Bought)
+            .ok_or(anyhow::anyhow!(
+                "carrier has no leg waiting for the shipment"
+            ))?;
+
+        leg.unassign();
+
+        carrier.drop_shipment(self.id);
+        carrier.unload_cargo(self.info.cargo_load());
+        carrier.release_collateral(self.info.value);
+
+        Ok(())
+    }
+
+    /// Carriers holding legs after the current one.
+    pub fn waiting_carriers(&self) -> Vec<Principal> {
+        self.legs
+            .iter()
+            .filter(|leg| *leg.status() == LegStatus::Bought && leg.carrier() != self.carrier)
+            .filter_map(Leg::carrier)
+            .collect()
+    }
+
+    /// Either the carrier or the customer, when confirming a handover outside
+    /// the geofence, can mark the shipment as picked up.
+    pub fn pickup(&mut self, caller: Principal, now: u64) -> anyhow::Result<()> {
+        if self.carrier != Some(caller) && caller != self.customer {
+            return Err(anyhow::anyhow!("caller is not a party of the shipment"));
+        }
+
+        if self.status != ShipmentStatus::Bought {
+            return Err(anyhow::anyhow!("shipment is not waiting for pickup"));
+        }
+
+        if let Some(window) = &self.info.pickup_window {
+            if now < window.start {
+                return Err(anyhow::anyhow!("pickup window has not started yet"));
+            }
+        }
+
+        self.status = ShipmentStatus::InTransit;
+        self.picked_up_at = Some(now);
+
+        if let Some(leg) = self.legs.get_mut(self.current_leg as usize) {
+            leg.start();
+        }
+
+        Ok(())
+    }
+
+    /// Records windows missed as of `now` and returns only the breaches not seen before.
+    pub fn check_sla(&mut self, now: u64) -> Vec<SlaBreach> {
+        if self.carrier.is_none() {
+            return vec![];
+        }
+
+        let mut breaches = vec![];
+
+        if let Some(window) = &self.info.pickup_window {
+            if self.picked_up_at.or(self.delivered_at).unwrap_or(now) > window.end {
+                breaches.push(SlaBreach::LatePickup);
+            }
+        }
+
+        if let Some(window) = &self.info.delivery_window {
+            if self.delivered_at.unwrap_or(now) > window.end {
+                breaches.push(SlaBreach::LateDelivery);
+            }
+        }
+
+        breaches.retain(|breach| !self.sla_breaches.contains(breach));
+        self.sla_breaches.extend(breaches.iter().copied());
+
+        breaches
+    }
+
+    /// Moves part of the carrier collateral locked for this shipment to the customer.
+    /// Returns the amount actually taken, which is capped by what is still locked.
+    pub fn apply_penalty(&mut self, carrier: &mut Carrier, amount: u64) -> anyhow::Result<u64> {
+        let amount = amount.min(self.collateral);
+        let penalty = checked_add(self.penalty, amount)?;
+
+        carrier.slash_collateral(amount)?;
+        self.collateral -= amount;
+        self.penalty = penalty;
+
+        Ok(amount)
+    }
+
+    /// A pending shipment expires once its listing is older than `ttl` or its
+    /// time windows can no longer be met by any carrier.
+    pub fn is_expired(&self, ttl: Option<u64>, now: u64) -> bool {
+        if self.status != ShipmentStatus::Pending {
+            return false;
+        }
+
+        let ttl_passed = ttl.is_some_and(|ttl| now >= self.listed_at.saturating_add(ttl));
+
+        ttl_passed || !self.info.can_be_met(now)
+    }
+
+    /// Cancels an unbought shipment. Returns the amount owed back to the customer,
+    /// which includes penalties collected from previous carriers.
+    pub fn expire(&mut self, customer: &mut Customer) -> anyhow::Result<u64> {
+        if self.status != ShipmentStatus::Pending {
+            return Err(anyhow::anyhow!("shipment is not pending"));
+        }
+
+        let refund = checked_add(self.escrowed_price(), self.penalty)?;
+
+        self.status = ShipmentStatus::Cancelled;
+        customer.cancel_shipment(self.id());
+
+        Ok(refund)
+    }
+
+    /// The carrier has until `timeout` after buying, or the end of the pickup
+    /// window if that is later, to pick the shipment up.
+    pub fn is_pickup_overdue(&self, timeout: Option<u64>, now: u64) -> bool {
+        if self.status != ShipmentStatus::Bought {
+            return false;
+        }
+
+        let (Some(timeout), Some(bought_at)) = (timeout, self.bought_at) else {
+            return false;
+        };
+
+        let window_end = self
+            .info
+            .pickup_window
+            .as_ref()
+            .map_or(0, |window| window.end);
+
+        now >= bought_at.saturating_add(timeout).max(window_end)
+    }
+
+    /// Takes the shipment away from a carrier that never picked it up and puts it
+    /// back on the market, charging `penalty` from the carrier collateral first.
+    pub fn revert_to_pending(
+        &mut self,
+        carrier: &mut Carrier,
+        penalty: u64,
+        now: u64,
+    ) -> anyhow::Result<u64> {
+        if self.status != ShipmentStatus::Bought {
+            return Err(anyhow::anyhow!("shipment is not waiting for pickup"));
+        }
+
+        let penalty = self.apply_penalty(carrier, penalty)?;
+        self.unassign(carrier, now);
+
+        Ok(penalty)
+    }
+
+    /// Releases the active carrier and puts the shipment back on the market.
+    fn unassign(&mut self, carrier: &mut Carrier, now: u64) {
+        carrier.drop_shipment(self.id());
+        carrier.unload_cargo(self.info.cargo_load());
+        carrier.release_collateral(self.collateral);
+
+        if let Some(leg) = self.legs.get_mut(self.current_leg as usize) {
+            leg.unassign();
+        }
+
+        self.collateral = 0;
+        self.carrier = None;
+        self.bought_at = None;
+        self.status = ShipmentStatus::Pending;
+        self.listed_at = now;
+    }
+
+    /// Moves the shipment to `status` on behalf of an admin, skipping the checks
+    /// the parties are held to. `carrier` is the active carrier, if there is one.
+    /// Carriers waiting for later legs have to be released before cancelling.
+    /// Returns the refund owed to the customer when the shipment is cancelled.
+    pub fn force_status(
+        &mut self,
+        status: ShipmentStatus,
+        carrier: Option<&mut Carrier>,
+        customer: &mut Customer,
+        now: u64,
+    ) -> anyhow::Result<Option<u64>> {
+        use ShipmentStatus::*;
+
+        if status == Cancelled && !self.waiting_carriers().is_empty() {
+            return Err(anyhow::anyhow!(
+                "carriers of later legs have to be released first"
+            ));
+        }
+
+        match (self.status.clone(), status, carrier) {
+            (Bought | InTransit, Pending, Some(carrier)) => self.unassign(carrier, now),
+            (Bought, InTransit, _) => {
+                self.status = InTransit;
+                self.picked_up_at = Some(now);
+
+                if let Some(leg) = self.legs.get_mut(self.current_leg as usize) {
+                    leg.start();
+                }
+            }
+            (Bought | InTransit, Delivered, Some(carrier)) => {
+                if self.next_leg().is_some() {
+                    return Err(anyhow::anyhow!(
+                        "shipment has to be handed over to the next carrier first"
+                    ));
+                }
+
+                self.deliver(carrier, customer, None, now);
+            }
+            (Bought | InTransit, Cancelled, Some(carrier)) => {
+                self.unassign(carrier, now);
+                return self.expire(customer).map(Some);
+            }
+            (Pending, Cancelled, _) => {
+                return self.expire(customer).map(Some);
+            }
+            (from, to, _) => {
+                return Err(anyhow::anyhow!(
+                    "cannot force a shipment from {from:?} to {to:?}"
+                ))
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Hands the active leg over to another carrier, e.g. when `from` lost
+    /// access to its account. The collateral moves along with it.
+    pub fn reassign_carrier(
+        &mut self,
+        from: &mut Carrier,
+        to: &mut Carrier,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        if !matches!(
+            self.status,
+            ShipmentStatus::Bought | ShipmentStatus::InTransit
+        ) {
+            return Err(anyhow::anyhow!("shipment has no active carrier"));
+        }
+
+        if self.carrier != Some(from.id()) {
+            return Err(anyhow::anyhow!("carrier is not the active carrier"));
+        }
+
+        if to.id() == self.customer {
+            return Err(anyhow::anyhow!(
+                "customers cannot carry their own shipments"
+            ));
+        }
+
+        if self.involves_carrier(to.id()) {
+            return Err(anyhow::anyhow!(
+                "carrier already holds a leg of this shipment"
+            ));
+        }
+
+        self.reserve(to)?;
+        from.drop_shipment(self.id());
+        from.unload_cargo(self.info.cargo_load());
+        from.release_collateral(self.collateral);
+
+        if let Some(leg) = self.legs.get_mut(self.current_leg as usize) {
+            let started = *leg.status() == LegStatus::InTransit;
+            leg.assign(to.id(), now);
+            if started {
+                leg.start();
+            }
+        }
+
+        self.carrier = Some(to.id());
+        self.collateral = self.info.value;
 
         Ok(())
     }
@@ -184,6 +1103,114 @@ impl Shipment {
         self.carrier
     }
 
+    pub fn recipient_id(&self) -> Option<Principal> {
+        self.recipient
+    }
+
+    /// Names who receives the shipment, `None` revokes their access.
+    pub fn set_recipient(
+        &mut self,
+        caller: Principal,
+        recipient: Option<Principal>,
+    ) -> anyhow::Result<()> {
+        if caller != self.customer {
+            return Err(anyhow::anyhow!("only the customer can set the recipient"));
+        }
+        if recipient == Some(Principal::anonymous()) {
+            return Err(anyhow::anyhow!("recipient cannot be anonymous"));
+        }
+
+        self.recipient = recipient;
+
+        Ok(())
+    }
+
+    /// The shipment as seen by someone who is not a party of it: the listing
+    /// stays visible, secrets, messages and evidence do not.
+    pub fn redacted(&self) -> Self {
+        Self {
+            hashed_secret: String::new(),
+            message: None,
+            recipient: None,
+            proof_of_delivery: None,
+            dispute: None,
+            carrier_ratings: vec![],
+            customer_ratings: vec![],
+            ..self.clone()
+        }
+    }
+
+    pub fn listing(&self) -> ShipmentListing {
+        ShipmentListing {
+            id: self.id,
+            name: self.name.clone(),
+            info: self.info.clone(),
+            status: self.status.clone(),
+            listed_at: self.listed_at,
+            legs: self.legs.iter().map(Leg::listing).collect(),
+        }
+    }
+
+    /// Carriers that held the shipment, each once: the carrier of a direct
+    /// shipment or the carriers of the legs.
+    pub fn carriers(&self) -> Vec<Principal> {
+        let mut carriers: Vec<Principal> = match self.legs.is_empty() {
+            true => self.carrier.into_iter().collect(),
+            false => self.legs.iter().filter_map(Leg::carrier).collect(),
+        };
+        carriers.sort();
+        carriers.dedup();
+
+        carriers
+    }
+
+    /// Whether `carrier` holds the shipment now or any of its legs.
+    pub fn involves_carrier(&self, carrier: Principal) -> bool {
+        self.carrier == Some(carrier) || self.legs.iter().any(|leg| leg.carrier() == Some(carrier))
+    }
+
+    /// Cancelled or paid out, nothing can happen to the shipment anymore.
+    pub fn is_finished(&self) -> bool {
+        self.status == ShipmentStatus::Cancelled || self.settlement.is_some()
+    }
+
+    /// Pending shipments, and multi-leg ones that still have legs without a carrier.
+    pub fn is_open_for_purchase(&self) -> bool {
+        match self.status {
+            ShipmentStatus::Pending => true,
+            ShipmentStatus::Bought | ShipmentStatus::InTransit => {
+                self.legs.iter().any(|leg| *leg.status() == LegStatus::Open)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn legs(&self) -> &[Leg] {
+        &self.legs
+    }
+
+    pub fn current_leg(&self) -> u32 {
+        self.current_leg
+    }
+
+    fn next_leg(&self) -> Option<&Leg> {
+        self.legs.get(self.current_leg as usize + 1)
+    }
+
+    /// Part of the price still held in escrow, since legs are paid out at
+    /// their handoff.
+    fn escrowed_price(&self) -> u64 {
+        match self.legs.is_empty() {
+            true => self.info.price,
+            false => self
+                .legs
+                .iter()
+                .filter(|leg| leg.settlement().is_none())
+                .map(Leg::price)
+                .sum(),
+        }
+    }
+
     pub fn id(&self) -> ShipmentIdInner {
         self.id
     }
@@ -191,32 +1218,507 @@ impl Shipment {
     pub fn info(&self) -> &ShipmentInfo {
         &self.info
     }
+
+    pub fn sla_breaches(&self) -> &[SlaBreach] {
+        &self.sla_breaches
+    }
+
+    pub fn penalty(&self) -> u64 {
+        self.penalty
+    }
+
+    pub fn collateral(&self) -> u64 {
+        self.collateral
+    }
+
+    pub fn proof_of_delivery(&self) -> Option<&ProofOfDelivery> {
+        self.proof_of_delivery.as_ref()
+    }
+
+    pub fn dispute(&self) -> Option<&Dispute> {
+        self.dispute.as_ref()
+    }
+
+    pub fn settlement(&self) -> Option<&Settlement> {
+        self.settlement.as_ref()
+    }
+}
+
+/// Amounts are user supplied, so sums of them have to be checked.
+fn checked_add(a: u64, b: u64) -> anyhow::Result<u64> {
+    a.checked_add(b)
+        .ok_or(anyhow::anyhow!("amount is too large"))
+}
+
+#[cfg(test)]
+mod hash_verify_test {
+    use super::*;
+    use crate::{
+        env::TestEnv,
+        models::profile::Profile,
+        test_utils::{HASH, SECRET},
+    };
+
+    fn shipment_with_hash(hash: &str) -> Shipment {
+        Shipment::create(
+            &TestEnv::new(0),
+            &mut Customer::new(Principal::anonymous(), Profile::new("Jacek".to_string())),
+            ShipmentIdInner::default(),
+            hash.to_string(),
+            "name".to_string(),
+            ShipmentInfo::new(
+                0,
+                0,
+                ShipmentLocation::new("street".to_string(), 0.0, 0.0),
+                ShipmentLocation::new("street".to_string(), 0.0, 0.0),
+                SizeCategory::Envelope,
+            ),
+        )
+    }
+
+    #[test]
+    fn test_hash_verify() {
+        let shipment = shipment_with_hash(HASH);
+
+        assert!(shipment.validate_secret(Some(SECRET.to_string())).is_ok());
+    }
+
+    #[test]
+    fn test_hash_verify_uppercase_hex() {
+        let shipment = shipment_with_hash(&HASH.to_uppercase());
+
+        assert!(shipment.validate_secret(Some(SECRET.to_string())).is_ok());
+    }
+
+    #[test]
+    fn test_hash_verify_wrong_secret() {
+        let shipment = shipment_with_hash(HASH);
+
+        assert!(shipment.validate_secret(Some("wrong".to_string())).is_err());
+        assert!(shipment.validate_secret(Some(String::new())).is_err());
+    }
+
+    #[test]
+    fn test_hash_verify_missing_secret() {
+        let shipment = shipment_with_hash(HASH);
+
+        assert!(shipment.validate_secret(None).is_err());
+    }
+
+    #[test]
+    fn test_hash_verify_invalid_hex() {
+        let shipment = shipment_with_hash("not hex");
+
+        assert!(shipment.validate_secret(Some(SECRET.to_string())).is_err());
+    }
+
+    #[test]
+    fn test_hash_verify_truncated_hash() {
+        let shipment = shipment_with_hash(&HASH[..32]);
+
+        assert!(shipment.validate_secret(Some(SECRET.to_string())).is_err());
+    }
+
+    #[test]
+    fn test_validate_listing() {
+        assert!(Shipment::validate_listing("name", HASH).is_ok());
+        assert!(Shipment::validate_listing("", HASH).is_err());
+        assert!(Shipment::validate_listing(&"n".repeat(MAX_NAME_LENGTH + 1), HASH).is_err());
+        assert!(Shipment::validate_listing("name", &HASH[..32]).is_err());
+        assert!(Shipment::validate_listing("name", &"z".repeat(HASHED_SECRET_LENGTH)).is_err());
+    }
 }
 
-// works, but cannot be used in tests, beacuse of icp code
-// #[cfg(test)]
-// mod hash_verify_test {
-//     use super::*;
-
-//     #[test]
-//     fn test_hash_verify() {
-//         let secret = "secret";
-//         let hex =  "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
-
-//         let shipment = Shipment::create(
-//             &mut Customer::new(Principal::anonymous(), "Jacek".to_string()),
-//             ShipmentIdInner::default(),
-//             hex.to_string(),
-//             "name".to_string(),
-//             ShipmentInfo::new(
-//                 0,
-//                 0,
-//                 ShipmentLocation::new("street".to_string(), 0.0, 0.0),
-//                 ShipmentLocation::new("street".to_string(), 0.0, 0.0),
-//                 SizeCategory::Envelope,
-//             ),
-//         );
-
-//         assert!(shipment.validate_secret(Some(secret.to_string())).is_ok());
-//     }
-// }
\ No newline at end of file
+#[cfg(test)]
+mod lifecycle_test {
+    use super::*;
+    use crate::{
+        env::TestEnv,
+        models::profile::Profile,
+        test_utils::{funded_carrier, location, principal, shipment_info, HASH, SECOND, SECRET},
+    };
+
+    fn setup(env: &TestEnv, info: ShipmentInfo) -> (Shipment, Customer) {
+        let mut customer = Customer::new(principal(1), Profile::new("Customer".to_string()));
+        let id = env.next_shipment_id();
+        let shipment = Shipment::create(
+            env,
+            &mut customer,
+            id,
+            HASH.to_string(),
+            "name".to_string(),
+            info,
+        );
+
+        (shipment, customer)
+    }
+
+    #[test]
+    fn test_validate_coordinates() {
+        assert!(shipment_info().validate(SECOND).is_ok());
+        assert!(location(-90.0, 180.0).validate().is_ok());
+
+        for (lat, lng) in [
+            (f64::NAN, 21.0),
+            (52.0, f64::NAN),
+            (f64::INFINITY, 21.0),
+            (52.0, f64::NEG_INFINITY),
+            (90.1, 21.0),
+            (52.0, -180.1),
+        ] {
+            assert!(location(lat, lng).validate().is_err());
+
+            let destination = ShipmentInfo::new(
+                100,
+                1_000,
+                location(52.2297, 21.0122),
+                location(lat, lng),
+                SizeCategory::Envelope,
+            );
+            assert!(destination.validate(SECOND).is_err());
+            assert!(shipment_info()
+                .with_handoff_points(vec![location(lat, lng)])
+                .validate(SECOND)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_oversized_amounts_do_not_overflow() {
+        let env = TestEnv::new(SECOND);
+        let huge = ShipmentInfo::new(
+            u64::MAX,
+            u64::MAX,
+            location(52.2297, 21.0122),
+            location(50.0647, 19.945),
+            SizeCategory::Envelope,
+        );
+        let (mut shipment, mut customer) = setup(&env, huge);
+        let mut first = funded_carrier(2);
+
+        shipment.buy(&mut first, env.now()).unwrap();
+        shipment.pickup(first.id(), env.now()).unwrap();
+        shipment
+            .finalize(
+                &mut first,
+                &mut customer,
+                Some(SECRET.to_string()),
+                None,
+                principal(2),
+                env.now(),
+            )
+            .unwrap();
+        let settlement = shipment.settle(&mut first, &FeeConfig::default()).unwrap();
+        assert_eq!(
+            settlement.carrier_payout + settlement.platform_fee,
+            u64::MAX
+        );
+
+        let huge = ShipmentInfo::new(
+            u64::MAX,
+            u64::MAX,
+            location(52.2297, 21.0122),
+            location(50.0647, 19.945),
+            SizeCategory::Envelope,
+        );
+        let (mut shipment, mut customer) = setup(&env, huge);
+        let mut second = funded_carrier(3);
+        shipment.buy(&mut second, env.now()).unwrap();
+        assert_eq!(shipment.apply_penalty(&mut second, 1).unwrap(), 1);
+        shipment
+            .force_status(
+                ShipmentStatus::Pending,
+                Some(&mut second),
+                &mut customer,
+                env.now(),
+            )
+            .unwrap();
+        assert!(shipment.expire(&mut customer).is_err());
+        assert_eq!(*shipment.status(), ShipmentStatus::Pending);
+    }
+
+    #[test]
+    fn test_direct_shipment_lifecycle() {
+        let env = TestEnv::new(SECOND);
+        let (mut shipment, mut customer) = setup(&env, shipment_info());
+        let mut carrier = funded_carrier(2);
+        env.set_caller(carrier.id());
+
+        shipment.buy(&mut carrier, env.now()).unwrap();
+        assert_eq!(*shipment.status(), ShipmentStatus::Bought);
+        assert_eq!(carrier.locked_collateral(), 100);
+        assert_eq!(carrier.shipments(), &[shipment.id()]);
+
+        env.advance(SECOND);
+        shipment.pickup(env.caller(), env.now()).unwrap();
+        assert_eq!(*shipment.status(), ShipmentStatus::InTransit);
+
+        env.advance(SECOND);
+        shipment
+            .finalize(
+                &mut carrier,
+                &mut customer,
+                Some(SECRET.to_string()),
+                None,
+                env.caller(),
+                env.now(),
+            )
+            .unwrap();
+        assert_eq!(*shipment.status(), ShipmentStatus::Delivered);
+        assert_eq!(carrier.shipments_done(), 1);
+        assert!(carrier.shipments().is_empty());
+
+        let window = 60 * SECOND;
+        assert!(!shipment.is_settleable(window, env.now()));
+
+        env.advance(window);
+        assert!(shipment.is_settleable(window, env.now()));
+
+        let fees = FeeConfig {
+            fee_bps: 100,
+            flat_fee: 0,
+        };
+        let settlement = shipment.settle(&mut carrier, &fees).unwrap();
+        assert_eq!(
+            settlement,
+            Settlement {
+                carrier_payout: 990,
+                customer_refund: 0,
+                platform_fee: 10,
+            }
+        );
+        assert_eq!(carrier.locked_collateral(), 0);
+        assert!(shipment.settle(&mut carrier, &fees).is_err());
+    }
+
+    #[test]
+    fn test_finalize_rejects_wrong_secret() {
+        let env = TestEnv::new(SECOND);
+        let (mut shipment, mut customer) = setup(&env, shipment_info());
+        let mut carrier = funded_carrier(2);
+
+        shipment.buy(&mut carrier, env.now()).unwrap();
+
+        let result = shipment.finalize(
+            &mut carrier,
+            &mut customer,
+            Some("wrong".to_string()),
+            None,
+            principal(2),
+            env.now(),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(*shipment.status(), ShipmentStatus::Bought);
+        assert_eq!(carrier.shipments_done(), 0);
+    }
+
+    #[test]
+    fn test_late_pickup_is_penalized() {
+        let env = TestEnv::new(SECOND);
+        let window = TimeWindow::new(SECOND, 10 * SECOND);
+        let (mut shipment, _) = setup(&env, shipment_info().with_time_windows(Some(window), None));
+        let mut carrier = funded_carrier(2);
+
+        shipment.buy(&mut carrier, env.now()).unwrap();
+        assert!(shipment.check_sla(env.now()).is_empty());
+
+        env.advance(20 * SECOND);
+        assert_eq!(shipment.check_sla(env.now()), vec![SlaBreach::LatePickup]);
+        assert!(shipment.check_sla(env.now()).is_empty());
+
+        assert_eq!(shipment.apply_penalty(&mut carrier, 30).unwrap(), 30);
+        assert_eq!(shipment.penalty(), 30);
+        assert_eq!(carrier.slashed_collateral(), 30);
+    }
+
+    #[test]
+    fn test_multi_leg_handoff() {
+        let env = TestEnv::new(SECOND);
+        let info = shipment_info().with_handoff_points(vec![location(51.2465, 22.5684)]);
+        let (mut shipment, mut customer) = setup(&env, info);
+        let mut first = funded_carrier(2);
+        let mut second = funded_carrier(3);
+
+        assert_eq!(shipment.legs().len(), 2);
+        assert_eq!(shipment.legs().iter().map(Leg::price).sum::<u64>(), 1_000);
+
+        shipment.buy(&mut first, env.now()).unwrap();
+        assert!(shipment.buy(&mut first, env.now()).is_err());
+        shipment.buy(&mut second, env.now()).unwrap();
+        assert!(!shipment.is_open_for_purchase());
+        assert_eq!(shipment.carrier_id(), Some(first.id()));
+
+        shipment.pickup(first.id(), env.now()).unwrap();
+        assert!(shipment
+            .finalize(
+                &mut first,
+                &mut customer,
+                Some(SECRET.to_string()),
+                None,
+                principal(2),
+                env.now(),
+            )
+            .is_err());
+
+        let fees = FeeConfig::default();
+        let first_leg = shipment.legs()[0].price();
+        let settlement = shipment
+            .confirm_handoff(&mut first, second.id(), &fees, env.now())
+            .unwrap();
+        assert_eq!(settlement.carrier_payout, first_leg);
+        assert_eq!(first.locked_collateral(), 0);
+        assert_eq!(first.shipments_done(), 1);
+        assert_eq!(shipment.carrier_id(), Some(second.id()));
+
+        shipment
+            .finalize(
+                &mut second,
+                &mut customer,
+                Some(SECRET.to_string()),
+                None,
+                principal(3),
+                env.now(),
+            )
+            .unwrap();
+
+        let settlement = shipment.settle(&mut second, &fees).unwrap();
+        assert_eq!(settlement.carrier_payout, 1_000 - first_leg);
+        assert_eq!(second.free_collateral(), u64::MAX);
+
+        // The customer and the carriers of both legs rate each other once.
+        let rating = |by: Principal| Rating::new(5, vec![], by, env.now()).unwrap();
+        let (customer_id, first_id, second_id) = (customer.id(), first.id(), second.id());
+        shipment
+            .rate(&mut first, &mut customer, rating(first_id))
+            .unwrap();
+        shipment
+            .rate(&mut first, &mut customer, rating(customer_id))
+            .unwrap();
+        shipment
+            .rate(&mut second, &mut customer, rating(customer_id))
+            .unwrap();
+        assert!(shipment
+            .rate(&mut first, &mut customer, rating(customer_id))
+            .is_err());
+        assert!(shipment
+            .rate(&mut first, &mut customer, rating(second_id))
+            .is_err());
+        assert!(shipment
+            .rate(&mut funded_carrier(4), &mut customer, rating(customer_id))
+            .is_err());
+        assert_eq!(first.reputation().ratings, 1);
+        assert_eq!(second.reputation().ratings, 1);
+        assert_eq!(customer.reputation().ratings, 1);
+    }
+
+    #[test]
+    fn test_admin_repairs() {
+        let env = TestEnv::new(SECOND);
+        let (mut shipment, mut customer) = setup(&env, shipment_info());
+        let mut first = funded_carrier(2);
+        let mut second = funded_carrier(3);
+
+        shipment.buy(&mut first, env.now()).unwrap();
+        shipment.pickup(first.id(), env.now()).unwrap();
+
+        assert!(shipment
+            .reassign_carrier(&mut second, &mut first, env.now())
+            .is_err());
+        shipment
+            .reassign_carrier(&mut first, &mut second, env.now())
+            .unwrap();
+        assert_eq!(shipment.carrier_id(), Some(second.id()));
+        assert_eq!(*shipment.status(), ShipmentStatus::InTransit);
+        assert_eq!(first.locked_collateral(), 0);
+        assert!(first.shipments().is_empty());
+        assert_eq!(second.locked_collateral(), 100);
+
+        assert!(shipment
+            .force_status(
+                ShipmentStatus::Disputed,
+                Some(&mut second),
+                &mut customer,
+                env.now()
+            )
+            .is_err());
+        shipment
+            .force_status(
+                ShipmentStatus::Delivered,
+                Some(&mut second),
+                &mut customer,
+                env.now(),
+            )
+            .unwrap();
+        assert_eq!(*shipment.status(), ShipmentStatus::Delivered);
+        assert_eq!(second.shipments_done(), 1);
+
+        let (mut shipment, mut customer) = setup(&env, shipment_info());
+        shipment.buy(&mut first, env.now()).unwrap();
+        shipment
+            .force_status(
+                ShipmentStatus::Pending,
+                Some(&mut first),
+                &mut customer,
+                env.now(),
+            )
+            .unwrap();
+        assert_eq!(shipment.carrier_id(), None);
+        assert_eq!(first.locked_collateral(), 0);
+
+        let refund = shipment
+            .force_status(ShipmentStatus::Cancelled, None, &mut customer, env.now())
+            .unwrap();
+        assert_eq!(refund, Some(1_000));
+        assert_eq!(*shipment.status(), ShipmentStatus::Cancelled);
+        assert!(customer.shipments().is_empty());
+    }
+
+    #[test]
+    fn test_customer_cannot_buy_own_shipment() {
+        let env = TestEnv::new(SECOND);
+        let (mut direct, customer) = setup(&env, shipment_info());
+        let (mut multi_leg, _) = setup(
+            &env,
+            shipment_info().with_handoff_points(vec![location(51.2465, 22.5684)]),
+        );
+        // Registered as a carrier under the principal of the customer.
+        let mut own = funded_carrier(1);
+        assert_eq!(own.id(), customer.id());
+
+        assert!(direct.buy(&mut own, env.now()).is_err());
+        assert!(multi_leg.buy_leg(&mut own, 1, env.now()).is_err());
+        assert_eq!(*direct.status(), ShipmentStatus::Pending);
+        assert!(own.shipments().is_empty());
+        assert_eq!(own.locked_collateral(), 0);
+    }
+
+    #[test]
+    fn test_cancelling_refunds_only_the_escrowed_price() {
+        let env = TestEnv::new(SECOND);
+        let info = shipment_info().with_handoff_points(vec![location(51.2465, 22.5684)]);
+        let (mut shipment, mut customer) = setup(&env, info);
+        let mut first = funded_carrier(2);
+        let mut second = funded_carrier(3);
+
+        shipment.buy(&mut first, env.now()).unwrap();
+        shipment.buy(&mut second, env.now()).unwrap();
+        shipment.pickup(first.id(), env.now()).unwrap();
+        let settlement = shipment
+            .confirm_handoff(&mut first, second.id(), &FeeConfig::default(), env.now())
+            .unwrap();
+
+        let refund = shipment
+            .force_status(
+                ShipmentStatus::Cancelled,
+                Some(&mut second),
+                &mut customer,
+                env.now(),
+            )
+            .unwrap();
+        let first_leg = shipment.legs()[0].price();
+        assert_eq!(settlement.carrier_payout, first_leg);
+        assert_eq!(refund, Some(1_000 - first_leg));
+        assert_eq!(second.locked_collateral(), 0);
+    }
+}
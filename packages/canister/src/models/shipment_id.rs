@@ -1,6 +1,6 @@
 use std::ops::{Deref, DerefMut};
 
-use crate::state::SHIPMENT_COUNTER;
+use crate::env::Environment;
 
 pub type ShipmentIdInner = u64;
 pub struct ShipmentId(ShipmentIdInner);
@@ -20,14 +20,8 @@ impl DerefMut for ShipmentId {
 }
 
 impl ShipmentId {
-    pub fn new() -> Self {
-        let id = SHIPMENT_COUNTER.with_borrow_mut(|counter| {
-            let current = *counter; 
-            *counter = current + 1;
-            current
-        });
-
-        Self(id)
+    pub fn new(env: &impl Environment) -> Self {
+        Self(env.next_shipment_id())
     }
 
     pub fn into_inner(self) -> ShipmentIdInner {
@@ -0,0 +1,233 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Cubic centimetres per volumetric kilogram, the usual courier convention.
+pub const VOLUMETRIC_DIVISOR: u64 = 5000;
+/// Longest side a regular parcel may have, in centimetres.
+pub const MAX_PARCEL_SIDE: u64 = 150;
+pub const MAX_PACKAGES: usize = 20;
+
+/// Dimensions are in centimetres.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub enum SizeCategory {
+    Envelope,
+    Parcel {
+        max_width: u64,
+        max_height: u64,
+        max_depth: u64,
+    },
+    Pallet {
+        max_width: u64,
+        max_height: u64,
+        max_depth: u64,
+    },
+    Oversize {
+        max_width: u64,
+        max_height: u64,
+        max_depth: u64,
+    },
+    MultiPackage(Vec<Package>),
+}
+
+/// One item of a multi-package shipment, it cannot be a multi-package itself.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Package {
+    pub size_category: SizeCategory,
+    pub weight_grams: u64,
+}
+
+/// Size classes ordered from smallest to largest.
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, CandidType, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum SizeClass {
+    Envelope,
+    Parcel,
+    Pallet,
+    Oversize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, CandidType, PartialEq, Eq)]
+pub struct Handling {
+    pub fragile: bool,
+    pub hazardous: bool,
+    pub perishable: bool,
+}
+
+impl SizeCategory {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            SizeCategory::Envelope => Ok(()),
+            SizeCategory::Parcel { .. } => {
+                let sides = self.boxes().concat();
+                if sides.contains(&0) {
+                    return Err(anyhow::anyhow!("parcel dimensions must be positive"));
+                }
+
+                if sides.iter().any(|side| *side > MAX_PARCEL_SIDE) {
+                    return Err(anyhow::anyhow!(
+                        "parcels are limited to {MAX_PARCEL_SIDE} cm per side, use oversize instead"
+                    ));
+                }
+
+                Ok(())
+            }
+            SizeCategory::Pallet { .. } | SizeCategory::Oversize { .. } => {
+                if self.boxes().concat().contains(&0) {
+                    return Err(anyhow::anyhow!("dimensions must be positive"));
+                }
+
+                Ok(())
+            }
+            SizeCategory::MultiPackage(packages) => {
+                if packages.is_empty() || packages.len() > MAX_PACKAGES {
+                    return Err(anyhow::anyhow!(
+                        "multi-package shipments need between 1 and {MAX_PACKAGES} packages"
+                    ));
+                }
+
+                for package in packages {
+                    if let SizeCategory::MultiPackage(_) = package.size_category {
+                        return Err(anyhow::anyhow!("packages cannot be nested"));
+                    }
+
+                    package.size_category.validate()?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Bounding boxes of every physical package, envelopes are not measured.
+    pub fn boxes(&self) -> Vec<[u64; 3]> {
+        match self {
+            SizeCategory::Envelope => vec![],
+            SizeCategory::Parcel {
+                max_width,
+                max_height,
+                max_depth,
+            }
+            | SizeCategory::Pallet {
+                max_width,
+                max_height,
+                max_depth,
+            }
+            | SizeCategory::Oversize {
+                max_width,
+                max_height,
+                max_depth,
+            } => vec![[*max_width, *max_height, *max_depth]],
+            SizeCategory::MultiPackage(packages) => packages
+                .iter()
+                .flat_map(|package| package.size_category.boxes())
+                .collect(),
+        }
+    }
+
+    pub fn volume(&self) -> u64 {
+        self.boxes()
+            .iter()
+            .map(|[w, h, d]| w.saturating_mul(*h).saturating_mul(*d))
+            .fold(0, u64::saturating_add)
+    }
+
+    pub fn volumetric_weight_grams(&self) -> u64 {
+        self.volume().saturating_mul(1000) / VOLUMETRIC_DIVISOR
+    }
+
+    /// Sum of the weights declared per package, only multi-package shipments have them.
+    pub fn package_weight_grams(&self) -> Option<u64> {
+        match self {
+            SizeCategory::MultiPackage(packages) => Some(
+                packages
+                    .iter()
+                    .map(|package| package.weight_grams)
+                    .fold(0, u64::saturating_add),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Multi-package shipments fall into the class of their largest package.
+    pub fn class(&self) -> SizeClass {
+        match self {
+            SizeCategory::Envelope => SizeClass::Envelope,
+            SizeCategory::Parcel { .. } => SizeClass::Parcel,
+            SizeCategory::Pallet { .. } => SizeClass::Pallet,
+            SizeCategory::Oversize { .. } => SizeClass::Oversize,
+            SizeCategory::MultiPackage(packages) => packages
+                .iter()
+                .map(|package| package.size_category.class())
+                .max()
+                .unwrap_or(SizeClass::Envelope),
+        }
+    }
+
+    pub fn package_count(&self) -> usize {
+        match self {
+            SizeCategory::MultiPackage(packages) => packages.len(),
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parcel(side: u64) -> SizeCategory {
+        SizeCategory::Parcel {
+            max_width: side,
+            max_height: side,
+            max_depth: side,
+        }
+    }
+
+    fn package(size_category: SizeCategory, weight_grams: u64) -> Package {
+        Package {
+            size_category,
+            weight_grams,
+        }
+    }
+
+    #[test]
+    fn test_parcel_dimensions() {
+        assert!(parcel(MAX_PARCEL_SIDE).validate().is_ok());
+        assert!(parcel(MAX_PARCEL_SIDE + 1).validate().is_err());
+        assert!(parcel(0).validate().is_err());
+        assert!(SizeCategory::Envelope.validate().is_ok());
+    }
+
+    #[test]
+    fn test_multi_packages_cannot_be_nested_or_empty() {
+        let nested = SizeCategory::MultiPackage(vec![package(
+            SizeCategory::MultiPackage(vec![package(parcel(10), 100)]),
+            100,
+        )]);
+
+        assert!(nested.validate().is_err());
+        assert!(SizeCategory::MultiPackage(vec![]).validate().is_err());
+    }
+
+    #[test]
+    fn test_multi_packages_add_up_their_packages() {
+        let pallet = SizeCategory::Pallet {
+            max_width: 100,
+            max_height: 100,
+            max_depth: 100,
+        };
+        let packages = SizeCategory::MultiPackage(vec![
+            package(parcel(10), 300),
+            package(pallet, 20_000),
+            package(SizeCategory::Envelope, 50),
+        ]);
+
+        assert!(packages.validate().is_ok());
+        assert_eq!(packages.class(), SizeClass::Pallet);
+        assert_eq!(packages.package_count(), 3);
+        assert_eq!(packages.package_weight_grams(), Some(20_350));
+        assert_eq!(packages.volume(), 1_000 + 1_000_000);
+        assert_eq!(packages.volumetric_weight_grams(), 200_200);
+    }
+}
@@ -0,0 +1,34 @@
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, CandidType, PartialEq, Eq)]
+pub enum SlaBreach {
+    LatePickup,
+    LateDelivery,
+}
+
+/// Penalties are given in basis points of the shipment value, zero disables them.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct SlaConfig {
+    pub late_pickup_penalty_bps: u16,
+    pub late_delivery_penalty_bps: u16,
+}
+
+impl SlaConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.late_pickup_penalty_bps > 10_000 || self.late_delivery_penalty_bps > 10_000 {
+            return Err(anyhow::anyhow!("penalty cannot exceed 10000 basis points"));
+        }
+
+        Ok(())
+    }
+
+    pub fn penalty(&self, breach: SlaBreach, value: u64) -> u64 {
+        let bps = match breach {
+            SlaBreach::LatePickup => self.late_pickup_penalty_bps,
+            SlaBreach::LateDelivery => self.late_delivery_penalty_bps,
+        };
+
+        (value as u128 * bps as u128 / 10_000) as u64
+    }
+}
@@ -0,0 +1,72 @@
+use super::shipment::ShipmentLocation;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+pub const MAX_TRAIL_POINTS: usize = 100;
+/// Minimum time between two `LocationUpdated` events of one shipment, in nanoseconds.
+pub const LOCATION_EVENT_INTERVAL: u64 = 5 * 60 * 1_000_000_000;
+
+/// Position reported by a carrier, `accuracy_m` is the radius of uncertainty.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct LocationPoint {
+    pub lat: f64,
+    pub lng: f64,
+    pub accuracy_m: f64,
+    pub reported_at: u64,
+}
+
+impl LocationPoint {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lng) {
+            return Err(anyhow::anyhow!("invalid coordinates"));
+        }
+
+        if !self.accuracy_m.is_finite() || self.accuracy_m < 0.0 {
+            return Err(anyhow::anyhow!("invalid accuracy"));
+        }
+
+        Ok(())
+    }
+
+    pub fn distance_km(&self, location: &ShipmentLocation) -> f64 {
+        ShipmentLocation::new(String::new(), self.lat, self.lng).distance_km(location)
+    }
+}
+
+/// Most recent positions of a shipment, oldest first.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, CandidType)]
+pub struct Trail {
+    points: VecDeque<LocationPoint>,
+    last_event_at: Option<u64>,
+}
+
+impl Trail {
+    /// Appends `point`, dropping the oldest one once the trail is full.
+    /// Returns whether enough time has passed to announce it with an event.
+    pub fn record(&mut self, point: LocationPoint) -> bool {
+        let now = point.reported_at;
+
+        self.points.push_back(point);
+        while self.points.len() > MAX_TRAIL_POINTS {
+            self.points.pop_front();
+        }
+
+        let due = self
+            .last_event_at
+            .is_none_or(|last| now >= last.saturating_add(LOCATION_EVENT_INTERVAL));
+        if due {
+            self.last_event_at = Some(now);
+        }
+
+        due
+    }
+
+    pub fn latest(&self) -> Option<&LocationPoint> {
+        self.points.back()
+    }
+
+    pub fn points(&self) -> Vec<LocationPoint> {
+        self.points.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,108 @@
+use super::profile::VehicleClass;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_VEHICLES: usize = 10;
+
+/// Cargo space of a vehicle, dimensions in centimetres and load in grams.
+#[derive(Deserialize, Serialize, Debug, Clone, CandidType)]
+pub struct Vehicle {
+    pub class: VehicleClass,
+    pub cargo_width: u64,
+    pub cargo_height: u64,
+    pub cargo_depth: u64,
+    pub max_load_grams: u64,
+}
+
+impl Vehicle {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.cargo_width == 0 || self.cargo_height == 0 || self.cargo_depth == 0 {
+            return Err(anyhow::anyhow!("vehicle cargo dimensions must be positive"));
+        }
+
+        if self.max_load_grams == 0 {
+            return Err(anyhow::anyhow!("vehicle load limit must be positive"));
+        }
+
+        Ok(())
+    }
+
+    pub fn volume(&self) -> u64 {
+        self.cargo_width
+            .saturating_mul(self.cargo_height)
+            .saturating_mul(self.cargo_depth)
+    }
+
+    /// Whether a box of the given dimensions fits in the cargo space in any orientation.
+    pub fn fits(&self, dimensions: [u64; 3]) -> bool {
+        let mut cargo = [self.cargo_width, self.cargo_height, self.cargo_depth];
+        let mut item = dimensions;
+        cargo.sort_unstable();
+        item.sort_unstable();
+
+        item.iter()
+            .zip(cargo.iter())
+            .all(|(item, cargo)| item <= cargo)
+    }
+}
+
+/// Space and weight taken by shipments a carrier currently holds.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, CandidType, PartialEq, Eq)]
+pub struct CargoLoad {
+    pub volume: u64,
+    pub weight_grams: u64,
+}
+
+impl CargoLoad {
+    pub fn add(&mut self, other: CargoLoad) {
+        self.volume = self.volume.saturating_add(other.volume);
+        self.weight_grams = self.weight_grams.saturating_add(other.weight_grams);
+    }
+
+    pub fn remove(&mut self, other: CargoLoad) {
+        self.volume = self.volume.saturating_sub(other.volume);
+        self.weight_grams = self.weight_grams.saturating_sub(other.weight_grams);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn van() -> Vehicle {
+        Vehicle {
+            class: VehicleClass::Van,
+            cargo_width: 120,
+            cargo_height: 100,
+            cargo_depth: 200,
+            max_load_grams: 800_000,
+        }
+    }
+
+    #[test]
+    fn test_boxes_fit_in_any_orientation() {
+        let van = van();
+
+        assert!(van.fits([200, 120, 100]));
+        assert!(van.fits([100, 200, 50]));
+        assert!(!van.fits([130, 130, 10]));
+        assert!(!van.fits([201, 10, 10]));
+    }
+
+    #[test]
+    fn test_cargo_space_must_be_positive() {
+        assert!(van().validate().is_ok());
+
+        let flat = Vehicle {
+            cargo_height: 0,
+            ..van()
+        };
+        assert!(flat.validate().is_err());
+
+        let weak = Vehicle {
+            max_load_grams: 0,
+            ..van()
+        };
+        assert!(weak.validate().is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    env::Environment,
+    models::shipment_id::ShipmentIdInner,
+    state::{COLLATERAL_PROCESSING, PROCESSING},
+};
+use candid::{CandidType, Deserialize, Principal};
+
+/// How long a shipment may stay locked before the jobs consider the
+/// operation stuck and release it, in nanoseconds.
+pub const PROCESSING_TIMEOUT: u64 = 10 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Buy,
+    Pickup,
+    Finalize,
+    Handoff,
+    ResolveDispute,
+    Settle,
+    /// Manual intervention by an admin.
+    Repair,
+}
+
+/// Intermediate state of a shipment while an operation on it is in flight.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Processing {
+    pub operation: Operation,
+    pub caller: Principal,
+    pub started_at: u64,
+}
+
+impl Processing {
+    pub fn is_stuck(&self, now: u64) -> bool {
+        now >= self.started_at.saturating_add(PROCESSING_TIMEOUT)
+    }
+}
+
+/// Exclusive hold on a shipment for the duration of one operation, including
+/// across await points. Changes are only committed by the operation once its
+/// calls succeeded, so releasing the guard is the only compensation needed
+/// when a callback fails: ic-cdk drops the guard when the callback traps.
+pub struct ShipmentGuard {
+    shipment_id: ShipmentIdInner,
+    started_at: u64,
+}
+
+impl ShipmentGuard {
+    pub fn acquire(
+        env: &impl Environment,
+        shipment_id: ShipmentIdInner,
+        operation: Operation,
+    ) -> anyhow::Result<Self> {
+        PROCESSING.with_borrow_mut(|processing| {
+            if processing.contains_key(&shipment_id) {
+                return Err(anyhow::anyhow!("Shipment is already being processed"));
+            }
+
+            let started_at = env.now();
+            processing.insert(
+                shipment_id,
+                Processing {
+                    operation,
+                    caller: env.caller(),
+                    started_at,
+                },
+            );
+
+            Ok(Self {
+                shipment_id,
+                started_at,
+            })
+        })
+    }
+}
+
+impl Drop for ShipmentGuard {
+    /// A guard released as stuck must not release the lock of a later operation.
+    fn drop(&mut self) {
+        PROCESSING.with_borrow_mut(|processing| {
+            if processing
+                .get(&self.shipment_id)
+                .is_some_and(|entry| entry.started_at == self.started_at)
+            {
+                processing.remove(&self.shipment_id);
+            }
+        });
+    }
+}
+
+/// Exclusive hold on a carrier's collateral while a ledger transfer for it is
+/// in flight, so that deposits and withdrawals of one carrier do not
+/// interleave across their await points. Like `ShipmentGuard`, it is dropped
+/// when the callback traps.
+pub struct CollateralGuard {
+    carrier: Principal,
+}
+
+impl CollateralGuard {
+    pub fn acquire(carrier: Principal) -> anyhow::Result<Self> {
+        COLLATERAL_PROCESSING.with_borrow_mut(|processing| {
+            if !processing.insert(carrier) {
+                return Err(anyhow::anyhow!("Collateral is already being processed"));
+            }
+
+            Ok(Self { carrier })
+        })
+    }
+}
+
+impl Drop for CollateralGuard {
+    fn drop(&mut self) {
+        COLLATERAL_PROCESSING.with_borrow_mut(|processing| processing.remove(&self.carrier));
+    }
+}
+
+pub fn get(shipment_id: ShipmentIdInner) -> Option<Processing> {
+    PROCESSING.with_borrow(|processing| processing.get(&shipment_id).cloned())
+}
+
+pub fn is_processing(shipment_id: ShipmentIdInner) -> bool {
+    PROCESSING.with_borrow(|processing| processing.contains_key(&shipment_id))
+}
+
+/// Releases the locks of operations that never completed, returning them.
+pub fn release_stuck(now: u64) -> Vec<(ShipmentIdInner, Processing)> {
+    PROCESSING.with_borrow_mut(|processing| {
+        let stuck: Vec<_> = processing
+            .iter()
+            .filter(|(_, entry)| entry.is_stuck(now))
+            .map(|(shipment_id, entry)| (*shipment_id, entry.clone()))
+            .collect();
+
+        for (shipment_id, _) in &stuck {
+            processing.remove(shipment_id);
+        }
+
+        stuck
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{env::TestEnv, test_utils::principal};
+
+    #[test]
+    fn test_guard_excludes_concurrent_operations() {
+        let env = TestEnv::new(1);
+
+        let guard = ShipmentGuard::acquire(&env, 7, Operation::Buy).unwrap();
+        assert!(ShipmentGuard::acquire(&env, 7, Operation::Finalize).is_err());
+        assert!(ShipmentGuard::acquire(&env, 8, Operation::Finalize).is_ok());
+        assert_eq!(get(7).unwrap().operation, Operation::Buy);
+
+        drop(guard);
+        assert!(!is_processing(7));
+        assert!(ShipmentGuard::acquire(&env, 7, Operation::Finalize).is_ok());
+    }
+
+    #[test]
+    fn test_collateral_guard_excludes_concurrent_transfers() {
+        let carrier = principal(2);
+
+        let guard = CollateralGuard::acquire(carrier).unwrap();
+        assert!(CollateralGuard::acquire(carrier).is_err());
+        assert!(CollateralGuard::acquire(principal(3)).is_ok());
+
+        drop(guard);
+        assert!(CollateralGuard::acquire(carrier).is_ok());
+    }
+
+    #[test]
+    fn test_stuck_operations_are_released() {
+        let env = TestEnv::new(1);
+
+        let guard = ShipmentGuard::acquire(&env, 7, Operation::Settle).unwrap();
+        assert!(release_stuck(env.now()).is_empty());
+
+        env.advance(PROCESSING_TIMEOUT);
+        let released = release_stuck(env.now());
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].1.operation, Operation::Settle);
+        assert!(!is_processing(7));
+
+        // Dropping the stale guard must not release the next operation.
+        env.advance(1);
+        let _next = ShipmentGuard::acquire(&env, 7, Operation::Settle).unwrap();
+        drop(guard);
+        assert!(is_processing(7));
+    }
+}
@@ -1,17 +1,37 @@
-use crate::models::{
-  carrier,
-  customer::{self, Customer, CustomerId},
-  shipment, shipment_id,
+use crate::{
+  models::{
+    carrier,
+    collateral::CollateralConfig,
+    customer::{self, Customer, CustomerId},
+    dispute::DisputeConfig,
+    expiry::ExpiryConfig,
+    fee::{FeeConfig, FeeLedger},
+    geofence::GeofenceConfig,
+    mode::OperationalMode,
+    pricing::PricingConfig,
+    profile::Profile,
+    quota::{QuotaConfig, Usage},
+    shipment, shipment_id,
+    sla::SlaConfig,
+    tracking::Trail,
+  },
+  processing::Processing,
 };
+use anyhow::anyhow;
+use candid::Principal;
 use std::{
   cell::RefCell,
-  collections::HashMap,
+  collections::{hash_map::Entry, HashMap, HashSet},
   ops::{Deref, DerefMut},
 };
 
 type CustomersStore = HashMap<customer::CustomerId, customer::Customer>;
 type ShipmentsStore = HashMap<shipment_id::ShipmentIdInner, shipment::Shipment>;
 type CarriersStore = HashMap<carrier::CarrierId, carrier::Carrier>;
+type TrailsStore = HashMap<shipment_id::ShipmentIdInner, Trail>;
+type ProcessingStore = HashMap<shipment_id::ShipmentIdInner, Processing>;
+type CollateralProcessingStore = HashSet<carrier::CarrierId>;
+type UsageStore = HashMap<Principal, Usage>;
 
 #[derive(Default)]
 pub struct Customers(CustomersStore);
@@ -67,11 +87,58 @@ impl DerefMut for Carriers {
 impl Shipments {
   pub fn get_all_pending(&self) -> Vec<shipment::Shipment> {
       self.values()
-          .filter(|shipment| *shipment.status() == shipment::ShipmentStatus::Pending)
+          .filter(|shipment| shipment.is_open_for_purchase())
           .cloned()
           .collect()
   }
 
+  pub fn get_ids_with_status(
+      &self,
+      status: shipment::ShipmentStatus,
+  ) -> Vec<shipment_id::ShipmentIdInner> {
+      self.values()
+          .filter(|shipment| *shipment.status() == status)
+          .map(|shipment| shipment.id())
+          .collect()
+  }
+
+  pub fn count_pending_for_customer(&self, customer_id: &CustomerId) -> usize {
+      self.values()
+          .filter(|shipment| {
+              shipment.customer_id() == *customer_id
+                  && *shipment.status() == shipment::ShipmentStatus::Pending
+          })
+          .count()
+  }
+
+  pub fn get_all_active_ids(&self) -> Vec<shipment_id::ShipmentIdInner> {
+      self.values()
+          .filter(|shipment| {
+              matches!(
+                  shipment.status(),
+                  shipment::ShipmentStatus::Bought | shipment::ShipmentStatus::InTransit
+              )
+          })
+          .map(|shipment| shipment.id())
+          .collect()
+  }
+
+  pub fn get_active_ids_for_carrier(
+      &self,
+      carrier_id: &carrier::CarrierId,
+  ) -> Vec<shipment_id::ShipmentIdInner> {
+      self.values()
+          .filter(|shipment| {
+              shipment.carrier_id() == Some(*carrier_id)
+                  && matches!(
+                      shipment.status(),
+                      shipment::ShipmentStatus::Bought | shipment::ShipmentStatus::InTransit
+                  )
+          })
+          .map(|shipment| shipment.id())
+          .collect()
+  }
+
   pub fn get_all_for_customer(&self, customer_id: &CustomerId) -> Vec<shipment::Shipment> {
       self.values()
           .filter(|shipment| shipment.customer_id() == *customer_id)
@@ -81,49 +148,192 @@ impl Shipments {
 
   pub fn get_all_for_shipper(&self, carrier_id: &carrier::CarrierId) -> Vec<shipment::Shipment> {
       self.values()
-          .filter(|shipment| shipment.carrier_id() == Some(*carrier_id))
+          .filter(|shipment| shipment.involves_carrier(*carrier_id))
           .cloned()
           .collect()
   }
 }
 
 impl Customers {
-  pub fn get_or_create(
-      &mut self,
-      customer_name: String,
-      customer_id: CustomerId,
-  ) -> &mut Customer {
-      let customer_exists = self.get_mut(&customer_id).is_some();
-
-      if !customer_exists {
-          let customer = Customer::new(customer_id, customer_name);
-          self.insert(customer_id, customer);
+  pub fn register(&mut self, customer_id: CustomerId, profile: Profile) -> anyhow::Result<()> {
+      if self.contains_key(&customer_id) {
+          return Err(anyhow::anyhow!("Customer already registered"));
       }
 
-      self.get_mut(&customer_id).expect("")
+      self.insert(customer_id, Customer::new(customer_id, profile));
+
+      Ok(())
   }
 }
 
 impl Carriers {
-  pub fn get_or_create(
+  pub fn register(
       &mut self,
       carrier_id: carrier::CarrierId,
-      carrier_name: String,
-  ) -> &mut carrier::Carrier {
-      let carrier_exists = self.get_mut(&carrier_id).is_some();
+      profile: Profile,
+  ) -> anyhow::Result<()> {
+      if self.contains_key(&carrier_id) {
+          return Err(anyhow::anyhow!("Carrier already registered"));
+      }
+
+      self.insert(carrier_id, carrier::Carrier::new(carrier_id, profile));
+
+      Ok(())
+  }
+}
 
-      if !carrier_exists {
-          let carrier = carrier::Carrier::new(carrier_id, carrier_name);
-          self.insert(carrier_id, carrier);
+/// Unit of work over the customer, carrier and shipment stores.
+///
+/// Entities are copied in on first access and only written back on commit, so
+/// an operation failing half way leaves no trace in the stores.
+#[derive(Default)]
+pub struct Transaction {
+  customers: CustomersStore,
+  carriers: CarriersStore,
+  shipments: ShipmentsStore,
+}
+
+impl Transaction {
+  pub fn customer(&mut self, customer_id: CustomerId) -> Option<&mut Customer> {
+      match self.customers.entry(customer_id) {
+          Entry::Occupied(entry) => Some(entry.into_mut()),
+          Entry::Vacant(entry) => {
+              let customer =
+                  CUSTOMERS.with_borrow(|customers| customers.get(&customer_id).cloned())?;
+              Some(entry.insert(customer))
+          }
       }
+  }
 
-      self.get_mut(&carrier_id).expect("")
+  pub fn carrier(&mut self, carrier_id: carrier::CarrierId) -> Option<&mut carrier::Carrier> {
+      match self.carriers.entry(carrier_id) {
+          Entry::Occupied(entry) => Some(entry.into_mut()),
+          Entry::Vacant(entry) => {
+              let carrier =
+                  CARRIERS.with_borrow(|carriers| carriers.get(&carrier_id).cloned())?;
+              Some(entry.insert(carrier))
+          }
+      }
+  }
+
+  pub fn shipment(
+      &mut self,
+      shipment_id: shipment_id::ShipmentIdInner,
+  ) -> Option<&mut shipment::Shipment> {
+      match self.shipments.entry(shipment_id) {
+          Entry::Occupied(entry) => Some(entry.into_mut()),
+          Entry::Vacant(entry) => {
+              let shipment =
+                  SHIPMENTS.with_borrow(|shipments| shipments.get(&shipment_id).cloned())?;
+              Some(entry.insert(shipment))
+          }
+      }
+  }
+
+  pub fn insert_shipment(&mut self, shipment: shipment::Shipment) {
+      self.shipments.insert(shipment.id(), shipment);
+  }
+
+  /// The shipment together with `carrier_id`'s carrier.
+  pub fn shipment_with_carrier(
+      &mut self,
+      shipment_id: shipment_id::ShipmentIdInner,
+      carrier_id: carrier::CarrierId,
+  ) -> anyhow::Result<(&mut shipment::Shipment, &mut carrier::Carrier)> {
+      self.shipment(shipment_id).ok_or(anyhow!("Shipment not found"))?;
+      self.carrier(carrier_id).ok_or(anyhow!("Carrier not found"))?;
+
+      match (self.shipments.get_mut(&shipment_id), self.carriers.get_mut(&carrier_id)) {
+          (Some(shipment), Some(carrier)) => Ok((shipment, carrier)),
+          _ => unreachable!(),
+      }
+  }
+
+  /// The shipment together with its customer.
+  pub fn shipment_with_customer(
+      &mut self,
+      shipment_id: shipment_id::ShipmentIdInner,
+  ) -> anyhow::Result<(&mut shipment::Shipment, &mut Customer)> {
+      let shipment = self.shipment(shipment_id).ok_or(anyhow!("Shipment not found"))?;
+      let customer_id = shipment.customer_id();
+      self.customer(customer_id).ok_or(anyhow!("Customer not found"))?;
+
+      match (self.shipments.get_mut(&shipment_id), self.customers.get_mut(&customer_id)) {
+          (Some(shipment), Some(customer)) => Ok((shipment, customer)),
+          _ => unreachable!(),
+      }
+  }
+
+  /// The shipment together with its active carrier and its customer.
+  pub fn parties(
+      &mut self,
+      shipment_id: shipment_id::ShipmentIdInner,
+  ) -> anyhow::Result<(&mut shipment::Shipment, &mut carrier::Carrier, &mut Customer)> {
+      let shipment = self.shipment(shipment_id).ok_or(anyhow!("Shipment not found"))?;
+      let carrier_id = shipment.carrier_id().ok_or(anyhow!("Carrier not set"))?;
+
+      self.parties_with_carrier(shipment_id, carrier_id)
+  }
+
+  /// The shipment together with `carrier_id`'s carrier and its customer.
+  pub fn parties_with_carrier(
+      &mut self,
+      shipment_id: shipment_id::ShipmentIdInner,
+      carrier_id: carrier::CarrierId,
+  ) -> anyhow::Result<(&mut shipment::Shipment, &mut carrier::Carrier, &mut Customer)> {
+      let shipment = self.shipment(shipment_id).ok_or(anyhow!("Shipment not found"))?;
+      let customer_id = shipment.customer_id();
+      self.carrier(carrier_id).ok_or(anyhow!("Carrier not found"))?;
+      self.customer(customer_id).ok_or(anyhow!("Customer not found"))?;
+
+      match (
+          self.shipments.get_mut(&shipment_id),
+          self.carriers.get_mut(&carrier_id),
+          self.customers.get_mut(&customer_id),
+      ) {
+          (Some(shipment), Some(carrier), Some(customer)) => Ok((shipment, carrier, customer)),
+          _ => unreachable!(),
+      }
+  }
+
+  pub fn commit(self) {
+      CUSTOMERS.with_borrow_mut(|customers| customers.extend(self.customers));
+      CARRIERS.with_borrow_mut(|carriers| carriers.extend(self.carriers));
+      SHIPMENTS.with_borrow_mut(|shipments| shipments.extend(self.shipments));
   }
 }
 
+/// Runs `f` in a transaction that is committed only when it returns `Ok`.
+pub fn transaction<T>(f: impl FnOnce(&mut Transaction) -> anyhow::Result<T>) -> anyhow::Result<T> {
+  let mut transaction = Transaction::default();
+  let result = f(&mut transaction)?;
+  transaction.commit();
+
+  Ok(result)
+}
+
 thread_local! {
   pub static CUSTOMERS: RefCell<Customers> = Default::default();
   pub static SHIPMENT_COUNTER: RefCell<u64> = Default::default();
   pub static SHIPMENTS: RefCell<Shipments> = Default::default();
   pub static CARRIERS: RefCell<Carriers> = Default::default();
-}
\ No newline at end of file
+  pub static SLA_CONFIG: RefCell<SlaConfig> = Default::default();
+  pub static EXPIRY_CONFIG: RefCell<ExpiryConfig> = Default::default();
+  pub static DISPUTE_CONFIG: RefCell<DisputeConfig> = Default::default();
+  pub static PRICING_CONFIG: RefCell<PricingConfig> = Default::default();
+  pub static FEE_CONFIG: RefCell<FeeConfig> = Default::default();
+  pub static FEE_LEDGER: RefCell<FeeLedger> = Default::default();
+  pub static GEOFENCE_CONFIG: RefCell<GeofenceConfig> = Default::default();
+  pub static COLLATERAL_CONFIG: RefCell<CollateralConfig> = Default::default();
+  pub static TRAILS: RefCell<TrailsStore> = Default::default();
+  pub static QUOTA_CONFIG: RefCell<QuotaConfig> = Default::default();
+  pub static USAGE: RefCell<UsageStore> = Default::default();
+  pub static MODE: RefCell<OperationalMode> = Default::default();
+  pub static LAST_JOBS_RUN: RefCell<Option<u64>> = Default::default();
+  // Operations in flight, deliberately not kept across upgrades.
+  pub static PROCESSING: RefCell<ProcessingStore> = Default::default();
+  pub static COLLATERAL_PROCESSING: RefCell<CollateralProcessingStore> = Default::default();
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,641 @@
+//! Model-based tests running random operation sequences through the endpoints
+//! in `api`. Every case runs on a fresh thread, so it gets a canister of its
+//! own, and checks the stores and the event log against each other.
+
+use super::{
+    transaction, Carriers, Customers, Shipments, CARRIERS, COLLATERAL_CONFIG, CUSTOMERS,
+    DISPUTE_CONFIG, EXPIRY_CONFIG, SHIPMENTS,
+};
+use crate::{
+    api,
+    env::{Environment, TestEnv},
+    jobs,
+    models::{
+        carrier::Carrier,
+        collateral::CollateralConfig,
+        customer::Customer,
+        dispute::{DisputeConfig, DisputeOutcome},
+        expiry::ExpiryConfig,
+        leg::{Leg, LegStatus},
+        profile::Profile,
+        shipment::{Shipment, ShipmentInfo, ShipmentStatus},
+        shipment_id::ShipmentIdInner,
+        size::SizeCategory,
+    },
+    test_utils::{location, shipment_info, HASH, SECOND, SECRET},
+    ShipmentEvent, TimestampedEvent, ARBITERS, EVENTS,
+};
+use anyhow::anyhow;
+use candid::Principal;
+use proptest::prelude::*;
+
+const PARTIES: u8 = 3;
+
+#[derive(Debug, Clone)]
+enum Op {
+    RegisterCustomer(u8),
+    RegisterCarrier(u8),
+    Deposit {
+        carrier: u8,
+        amount: u64,
+    },
+    Withdraw {
+        carrier: u8,
+        amount: u64,
+    },
+    Create {
+        customer: u8,
+        value: u64,
+        price: u64,
+        handoffs: usize,
+    },
+    Buy {
+        carrier: u8,
+        shipment: usize,
+    },
+    BuyLeg {
+        carrier: u8,
+        shipment: usize,
+        leg: u32,
+    },
+    Pickup {
+        caller: u8,
+        shipment: usize,
+    },
+    Handoff {
+        carrier: u8,
+        shipment: usize,
+    },
+    Finalize {
+        caller: u8,
+        shipment: usize,
+        valid_secret: bool,
+    },
+    Dispute {
+        caller: u8,
+        shipment: usize,
+    },
+    Resolve {
+        shipment: usize,
+        outcome: DisputeOutcome,
+    },
+    Rate {
+        caller: u8,
+        shipment: usize,
+        score: u8,
+        /// The carrier a customer rates, the delivering one if unset.
+        carrier: Option<u8>,
+    },
+    Advance(u64),
+    RunJobs,
+}
+
+fn outcome_strategy() -> impl Strategy<Value = DisputeOutcome> {
+    prop_oneof![
+        Just(DisputeOutcome::RefundCustomer),
+        Just(DisputeOutcome::PayCarrier),
+        (0..=10_000u16).prop_map(|customer_share_bps| DisputeOutcome::Split { customer_share_bps }),
+        (0..2_000u64).prop_map(|amount| DisputeOutcome::SlashCollateral { amount }),
+    ]
+}
+
+/// Weighted towards the operations that move shipments forward, so that
+/// most cases get past the purchase.
+fn op_strategy() -> impl Strategy<Value = Op> {
+    let party = 0..PARTIES;
+    let shipment = any::<usize>();
+    let handoffs = prop_oneof![2 => Just(0), 1 => 1..3usize];
+
+    prop_oneof![
+        2 => party.clone().prop_map(Op::RegisterCustomer),
+        2 => party.clone().prop_map(Op::RegisterCarrier),
+        2 => (party.clone(), 0..2_000u64)
+            .prop_map(|(carrier, amount)| Op::Deposit { carrier, amount }),
+        1 => (party.clone(), 0..2_000u64)
+            .prop_map(|(carrier, amount)| Op::Withdraw { carrier, amount }),
+        2 => (party.clone(), 0..1_000u64, 0..1_000u64, handoffs).prop_map(
+            |(customer, value, price, handoffs)| Op::Create {
+                customer,
+                value,
+                price,
+                handoffs,
+            }
+        ),
+        3 => (party.clone(), shipment)
+            .prop_map(|(carrier, shipment)| Op::Buy { carrier, shipment }),
+        3 => (party.clone(), shipment, 0..3u32).prop_map(|(carrier, shipment, leg)| Op::BuyLeg {
+            carrier,
+            shipment,
+            leg,
+        }),
+        3 => (party.clone(), shipment)
+            .prop_map(|(caller, shipment)| Op::Pickup { caller, shipment }),
+        3 => (party.clone(), shipment)
+            .prop_map(|(carrier, shipment)| Op::Handoff { carrier, shipment }),
+        4 => (party.clone(), shipment, prop::bool::weighted(0.8)).prop_map(
+            |(caller, shipment, valid_secret)| Op::Finalize {
+                caller,
+                shipment,
+                valid_secret,
+            }
+        ),
+        3 => (party.clone(), shipment)
+            .prop_map(|(caller, shipment)| Op::Dispute { caller, shipment }),
+        3 => (shipment, outcome_strategy())
+            .prop_map(|(shipment, outcome)| Op::Resolve { shipment, outcome }),
+        3 => (party.clone(), shipment, 0..7u8, prop::option::of(party)).prop_map(
+            |(caller, shipment, score, carrier)| Op::Rate {
+                caller,
+                shipment,
+                score,
+                carrier,
+            }
+        ),
+        2 => (1..60u64).prop_map(Op::Advance),
+        2 => Just(Op::RunJobs),
+    ]
+}
+
+/// The same principals act as customers and carriers, so the cases also
+/// cover accounts holding both roles.
+fn party(index: u8) -> Principal {
+    Principal::from_slice(&[1, index])
+}
+
+fn arbiter() -> Principal {
+    Principal::from_slice(&[2])
+}
+
+struct World {
+    env: TestEnv,
+    ids: Vec<ShipmentIdInner>,
+}
+
+impl World {
+    /// Configures the canister of the current thread with short timeouts, so
+    /// that jobs have something to do within a case.
+    fn new() -> Self {
+        EXPIRY_CONFIG.set(ExpiryConfig {
+            pending_ttl_secs: Some(600),
+            pickup_timeout_secs: Some(120),
+            abandonment_penalty_bps: 1_000,
+        });
+        DISPUTE_CONFIG.set(DisputeConfig { window_secs: 60 });
+        COLLATERAL_CONFIG.set(CollateralConfig {
+            ledger: Some(Principal::from_slice(&[3])),
+        });
+        ARBITERS.with_borrow_mut(|arbiters| arbiters.insert(arbiter()));
+
+        Self {
+            env: TestEnv::new(SECOND),
+            ids: vec![],
+        }
+    }
+
+    fn pick(&self, index: usize) -> Option<ShipmentIdInner> {
+        match self.ids.is_empty() {
+            true => None,
+            false => Some(self.ids[index % self.ids.len()]),
+        }
+    }
+
+    /// Runs `op` as the endpoints would. Whether it is accepted does not
+    /// matter, the invariants have to hold either way.
+    fn apply(&mut self, op: Op) {
+        let env = &self.env;
+
+        match op {
+            Op::RegisterCustomer(index) => {
+                env.set_caller(party(index));
+                let _ = api::register_customer(env, Profile::new("Customer".to_string()));
+            }
+            Op::RegisterCarrier(index) => {
+                env.set_caller(party(index));
+                let _ = api::register_carrier(env, Profile::new("Carrier".to_string()));
+            }
+            Op::Deposit { carrier, amount } => {
+                env.set_caller(party(carrier));
+                if api::check_deposit(env, amount).is_ok() {
+                    let _ = api::deposit_collateral(env, amount);
+                }
+            }
+            Op::Withdraw { carrier, amount } => {
+                env.set_caller(party(carrier));
+                if api::withdraw_collateral(env, amount).is_ok() {
+                    let _ = api::finish_withdrawal(env, amount, Ok(()));
+                }
+            }
+            Op::Create {
+                customer,
+                value,
+                price,
+                handoffs,
+            } => {
+                let handoff_points = [location(51.5, 20.7), location(50.8, 20.3)];
+                let info = ShipmentInfo::new(
+                    value,
+                    price,
+                    location(52.2297, 21.0122),
+                    location(50.0647, 19.945),
+                    SizeCategory::Envelope,
+                )
+                .with_handoff_points(handoff_points[..handoffs].to_vec());
+
+                env.set_caller(party(customer));
+                if let Ok(id) =
+                    api::create_shipment(env, "name".to_string(), HASH.to_string(), info)
+                {
+                    self.ids.push(id);
+                }
+            }
+            Op::Buy { carrier, shipment } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(carrier));
+                let _ = api::buy_shipment(env, id);
+            }
+            Op::BuyLeg {
+                carrier,
+                shipment,
+                leg,
+            } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(carrier));
+                let _ = api::buy_shipment_leg(env, id, leg);
+            }
+            Op::Pickup { caller, shipment } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(caller));
+                let _ = api::pickup_shipment(env, id, None);
+            }
+            Op::Handoff { carrier, shipment } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(carrier));
+                let _ = api::confirm_handoff(env, id);
+            }
+            Op::Finalize {
+                caller,
+                shipment,
+                valid_secret,
+            } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                let secret = match valid_secret {
+                    true => SECRET,
+                    false => "wrong",
+                };
+                env.set_caller(party(caller));
+                let _ = api::finalize_shipment(env, id, Some(secret.to_string()), None);
+            }
+            Op::Dispute { caller, shipment } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(caller));
+                let _ = api::open_dispute(env, id, "damaged".to_string(), String::new());
+            }
+            Op::Resolve { shipment, outcome } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(arbiter());
+                let _ = api::resolve_dispute(env, id, outcome);
+            }
+            Op::Rate {
+                caller,
+                shipment,
+                score,
+                carrier,
+            } => {
+                let Some(id) = self.pick(shipment) else {
+                    return;
+                };
+                env.set_caller(party(caller));
+                let _ = api::rate_shipment(env, id, score, vec![], carrier.map(party));
+            }
+            Op::Advance(secs) => env.advance(secs * SECOND),
+            Op::RunJobs => jobs::run(env),
+        }
+    }
+
+    fn check_invariants(&self) {
+        let events: Vec<ShipmentEvent> = EVENTS.with_borrow(|events| {
+            events
+                .iter()
+                .map(|event: &TimestampedEvent| event.event.clone())
+                .collect()
+        });
+
+        SHIPMENTS.with_borrow(|shipments| {
+            CARRIERS.with_borrow(|carriers| {
+                CUSTOMERS.with_borrow(|customers| {
+                    for shipment in shipments.values() {
+                        check_shipment(shipment, &events);
+                        check_holders(shipment, carriers, customers);
+                    }
+
+                    for carrier in carriers.values() {
+                        check_carrier(carrier, shipments, &events);
+                    }
+
+                    for customer in customers.values() {
+                        check_customer(customer, shipments, &events);
+                    }
+
+                    // Slashed collateral is owed to exactly one shipment's customer.
+                    let slashed: u64 = carriers.values().map(Carrier::slashed_collateral).sum();
+                    let penalties: u64 = shipments.values().map(Shipment::penalty).sum();
+                    assert_eq!(slashed, penalties);
+                })
+            })
+        });
+    }
+}
+
+/// A shipment is listed by its active carrier and those waiting for a later
+/// leg, and by nobody else.
+fn check_holders(shipment: &Shipment, carriers: &Carriers, customers: &Customers) {
+    let id = shipment.id();
+    let active = matches!(
+        shipment.status(),
+        ShipmentStatus::Bought | ShipmentStatus::InTransit
+    );
+
+    let mut holders: Vec<Principal> = carriers
+        .values()
+        .filter(|carrier| carrier.shipments().contains(&id))
+        .map(Carrier::id)
+        .collect();
+    let mut expected = shipment.waiting_carriers();
+    if active {
+        expected.extend(shipment.carrier_id());
+    }
+    holders.sort();
+    expected.sort();
+    assert_eq!(holders, expected, "holders of {id}");
+
+    let customer = &customers[&shipment.customer_id()];
+    assert_eq!(
+        customer.shipments().contains(&id),
+        active || *shipment.status() == ShipmentStatus::Pending
+    );
+}
+
+/// Checks the counters and collateral of `carrier` against the shipments and
+/// the event log.
+fn check_carrier(carrier: &Carrier, shipments: &Shipments, events: &[ShipmentEvent]) {
+    let id = carrier.id();
+    let carried = |shipment_id: &ShipmentIdInner| shipments[shipment_id].carrier_id() == Some(id);
+
+    let done = events
+        .iter()
+        .filter(|event| match event {
+            ShipmentEvent::Finalized { shipment_id } => carried(shipment_id),
+            ShipmentEvent::HandoffConfirmed { from, .. } => *from == id,
+            _ => false,
+        })
+        .count();
+    assert_eq!(carrier.shipments_done() as usize, done);
+
+    let ratings = events
+        .iter()
+        .filter(|event| match event {
+            ShipmentEvent::Rated {
+                shipment_id,
+                rated_by,
+                rated,
+                ..
+            } => *rated == id && *rated_by == shipments[shipment_id].customer_id(),
+            _ => false,
+        })
+        .count();
+    assert_eq!(carrier.reputation().ratings as usize, ratings);
+
+    let locked: u64 = shipments
+        .values()
+        .map(|shipment| {
+            let active = match carried(&shipment.id()) && shipment.settlement().is_none() {
+                true => shipment.collateral(),
+                false => 0,
+            };
+            let waiting = shipment
+                .waiting_carriers()
+                .iter()
+                .filter(|carrier| **carrier == id)
+                .count() as u64;
+
+            active + waiting * shipment.info().value()
+        })
+        .sum();
+    assert_eq!(carrier.locked_collateral(), locked);
+
+    let deposited = events.iter().fold(0, |deposited, event| match event {
+        ShipmentEvent::CollateralDeposited { carrier, amount } if *carrier == id => {
+            deposited + amount
+        }
+        ShipmentEvent::CollateralWithdrawn { carrier, amount } if *carrier == id => {
+            deposited - amount
+        }
+        _ => deposited,
+    });
+    assert_eq!(
+        carrier.free_collateral(),
+        deposited
+            .saturating_sub(carrier.locked_collateral())
+            .saturating_sub(carrier.slashed_collateral())
+    );
+}
+
+fn check_customer(customer: &Customer, shipments: &Shipments, events: &[ShipmentEvent]) {
+    let owned =
+        |shipment_id: &ShipmentIdInner| shipments[shipment_id].customer_id() == customer.id();
+
+    let sent = events
+        .iter()
+        .filter(|event| match event {
+            ShipmentEvent::Finalized { shipment_id } => owned(shipment_id),
+            _ => false,
+        })
+        .count();
+    assert_eq!(customer.shipments_sent() as usize, sent);
+
+    let ratings = events
+        .iter()
+        .filter(|event| match event {
+            ShipmentEvent::Rated {
+                shipment_id, rated, ..
+            } => owned(shipment_id) && *rated == customer.id(),
+            _ => false,
+        })
+        .count();
+    assert_eq!(customer.reputation().ratings as usize, ratings);
+}
+
+/// Checks that the payouts recorded for `shipment` account for its price.
+fn check_shipment(shipment: &Shipment, events: &[ShipmentEvent]) {
+    let id = shipment.id();
+    let legs = shipment.legs();
+
+    assert_ne!(shipment.carrier_id(), Some(shipment.customer_id()));
+    assert!(legs
+        .iter()
+        .all(|leg| leg.carrier() != Some(shipment.customer_id())));
+    if !legs.is_empty() {
+        assert_eq!(
+            legs.iter().map(Leg::price).sum::<u64>(),
+            shipment.info().price()
+        );
+    }
+
+    let escrowed = match legs.is_empty() {
+        true => shipment.info().price(),
+        false => legs
+            .iter()
+            .filter(|leg| leg.settlement().is_none())
+            .map(Leg::price)
+            .sum(),
+    };
+
+    let mut final_settlements = vec![];
+    let mut leg_settlements = 0;
+    let mut refunds = vec![];
+    for event in events {
+        match event {
+            ShipmentEvent::Settled {
+                shipment_id,
+                leg,
+                settlement,
+            } if *shipment_id == id => match leg {
+                Some(_) => leg_settlements += 1,
+                None => final_settlements.push(settlement.clone()),
+            },
+            ShipmentEvent::Expired {
+                shipment_id,
+                refund,
+            } if *shipment_id == id => refunds.push(*refund),
+            _ => {}
+        }
+    }
+
+    // A handed over leg pays its price, minus the fee. The collateral goes
+    // back to the carrier's deposit, not into the payout.
+    for leg in legs {
+        if let Some(settlement) = leg.settlement() {
+            assert_eq!(*leg.status(), LegStatus::Completed);
+            assert_eq!(settlement.customer_refund, 0);
+            assert_eq!(
+                settlement.carrier_payout + settlement.platform_fee,
+                leg.price()
+            );
+        }
+    }
+    assert_eq!(
+        leg_settlements,
+        legs.iter().filter(|leg| leg.settlement().is_some()).count()
+    );
+
+    match shipment.settlement() {
+        Some(settlement) => {
+            assert_eq!(final_settlements, vec![settlement.clone()]);
+            assert!(refunds.is_empty());
+
+            // What is left in escrow is split between the carrier and the
+            // customer, who also gets all the penalties.
+            let share = settlement.customer_refund - shipment.penalty();
+            let earned = escrowed - share;
+            assert_eq!(settlement.carrier_payout + settlement.platform_fee, earned);
+        }
+        None => {
+            assert!(final_settlements.is_empty());
+
+            match *shipment.status() == ShipmentStatus::Cancelled {
+                true => assert_eq!(refunds, vec![escrowed + shipment.penalty()]),
+                false => assert!(refunds.is_empty()),
+            }
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn test_random_operations_keep_invariants(ops in prop::collection::vec(op_strategy(), 1..150)) {
+        let case = std::thread::spawn(move || {
+            let mut world = World::new();
+
+            for op in ops {
+                world.apply(op);
+                world.check_invariants();
+            }
+        });
+
+        if let Err(panic) = case.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+}
+
+fn create_in_transaction(env: &TestEnv, fail: bool) -> anyhow::Result<ShipmentIdInner> {
+    transaction(|tx| {
+        let customer = tx.customer(party(0)).ok_or(anyhow!("Customer not found"))?;
+        let info = shipment_info();
+        let id = env.next_shipment_id();
+        let shipment = Shipment::create(
+            env,
+            customer,
+            id,
+            HASH.to_string(),
+            "name".to_string(),
+            info,
+        );
+        tx.insert_shipment(shipment);
+
+        match fail {
+            true => Err(anyhow!("failed after the writes")),
+            false => Ok(id),
+        }
+    })
+}
+
+#[test]
+fn test_failed_transaction_leaves_no_trace() {
+    let env = TestEnv::new(SECOND);
+    CUSTOMERS
+        .with_borrow_mut(|customers| {
+            customers.register(party(0), Profile::new("Customer".to_string()))
+        })
+        .unwrap();
+
+    assert!(create_in_transaction(&env, true).is_err());
+
+    SHIPMENTS.with_borrow(|shipments| assert!(shipments.is_empty()));
+    CUSTOMERS.with_borrow(|customers| {
+        assert!(customers[&party(0)].shipments().is_empty());
+    });
+}
+
+#[test]
+fn test_transaction_commits_every_entity() {
+    let env = TestEnv::new(SECOND);
+    CUSTOMERS
+        .with_borrow_mut(|customers| {
+            customers.register(party(0), Profile::new("Customer".to_string()))
+        })
+        .unwrap();
+
+    let id = create_in_transaction(&env, false).unwrap();
+
+    SHIPMENTS.with_borrow(|shipments| assert!(shipments.contains_key(&id)));
+    CUSTOMERS.with_borrow(|customers| {
+        assert!(customers[&party(0)].shipments().contains(&id));
+    });
+}
@@ -0,0 +1,44 @@
+//! Fixtures shared by the unit tests.
+
+use crate::models::{
+    carrier::Carrier,
+    profile::Profile,
+    shipment::{ShipmentInfo, ShipmentLocation},
+    size::SizeCategory,
+};
+use candid::Principal;
+
+pub const SECOND: u64 = 1_000_000_000;
+pub const SECRET: &str = "secret";
+/// SHA-256 of `SECRET`.
+pub const HASH: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+pub fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}
+
+pub fn location(lat: f64, lng: f64) -> ShipmentLocation {
+    ShipmentLocation::new("street".to_string(), lat, lng)
+}
+
+/// An envelope worth 100 sent from Warsaw to Kraków for 1000.
+pub fn shipment_info() -> ShipmentInfo {
+    ShipmentInfo::new(
+        100,
+        1_000,
+        location(52.2297, 21.0122),
+        location(50.0647, 19.945),
+        SizeCategory::Envelope,
+    )
+}
+
+pub fn carrier(id: u8) -> Carrier {
+    Carrier::new(principal(id), Profile::new("Carrier".to_string()))
+}
+
+/// A carrier that deposited enough collateral to buy any shipment.
+pub fn funded_carrier(id: u8) -> Carrier {
+    let mut carrier = carrier(id);
+    carrier.deposit_collateral(u64::MAX).unwrap();
+    carrier
+}
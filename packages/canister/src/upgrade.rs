@@ -0,0 +1,154 @@
+use crate::{
+    jobs,
+    models::{
+        carrier::{Carrier, CarrierId},
+        collateral::CollateralConfig,
+        customer::{Customer, CustomerId},
+        dispute::DisputeConfig,
+        expiry::ExpiryConfig,
+        fee::{FeeConfig, FeeLedger},
+        geofence::GeofenceConfig,
+        mode::OperationalMode,
+        pricing::PricingConfig,
+        quota::{QuotaConfig, Usage},
+        shipment::Shipment,
+        shipment_id::ShipmentIdInner,
+        sla::SlaConfig,
+        tracking::Trail,
+    },
+    state::{
+        CARRIERS, COLLATERAL_CONFIG, CUSTOMERS, DISPUTE_CONFIG, EXPIRY_CONFIG, FEE_CONFIG,
+        FEE_LEDGER, GEOFENCE_CONFIG, MODE, PRICING_CONFIG, QUOTA_CONFIG, SHIPMENTS,
+        SHIPMENT_COUNTER, SLA_CONFIG, TRAILS, USAGE,
+    },
+    TimestampedEvent, ADMINS, ARBITERS, AUDITORS, BANNED, EVENTS, LAST_SEQUENCE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{post_upgrade, pre_upgrade};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Layout of `StableState`, bumped whenever it changes in a way older code
+/// cannot read back.
+const STATE_VERSION: u32 = 1;
+
+/// Snapshot of every thread local, written to stable memory across upgrades.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    version: u32,
+    customers: HashMap<CustomerId, Customer>,
+    shipments: HashMap<ShipmentIdInner, Shipment>,
+    carriers: HashMap<CarrierId, Carrier>,
+    shipment_counter: u64,
+    trails: HashMap<ShipmentIdInner, Trail>,
+    sla_config: SlaConfig,
+    expiry_config: ExpiryConfig,
+    dispute_config: DisputeConfig,
+    pricing_config: PricingConfig,
+    quota_config: QuotaConfig,
+    usage: HashMap<Principal, Usage>,
+    fee_config: FeeConfig,
+    fee_ledger: FeeLedger,
+    geofence_config: GeofenceConfig,
+    collateral_config: CollateralConfig,
+    mode: OperationalMode,
+    events: VecDeque<TimestampedEvent>,
+    last_sequence: u64,
+    admins: HashSet<Principal>,
+    arbiters: HashSet<Principal>,
+    auditors: HashSet<Principal>,
+    banned: HashSet<Principal>,
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        version: STATE_VERSION,
+        customers: CUSTOMERS.with_borrow_mut(|customers| std::mem::take(&mut **customers)),
+        shipments: SHIPMENTS.with_borrow_mut(|shipments| std::mem::take(&mut **shipments)),
+        carriers: CARRIERS.with_borrow_mut(|carriers| std::mem::take(&mut **carriers)),
+        shipment_counter: SHIPMENT_COUNTER.with_borrow(|counter| *counter),
+        trails: TRAILS.take(),
+        sla_config: SLA_CONFIG.take(),
+        expiry_config: EXPIRY_CONFIG.take(),
+        dispute_config: DISPUTE_CONFIG.take(),
+        pricing_config: PRICING_CONFIG.take(),
+        quota_config: QUOTA_CONFIG.take(),
+        usage: USAGE.take(),
+        fee_config: FEE_CONFIG.take(),
+        fee_ledger: FEE_LEDGER.take(),
+        geofence_config: GEOFENCE_CONFIG.take(),
+        collateral_config: COLLATERAL_CONFIG.take(),
+        mode: MODE.take(),
+        events: EVENTS.take(),
+        last_sequence: LAST_SEQUENCE.take(),
+        admins: ADMINS.take(),
+        arbiters: ARBITERS.take(),
+        auditors: AUDITORS.take(),
+        banned: BANNED.take(),
+    };
+
+    ic_cdk::storage::stable_save((state,)).expect("failed to save state to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Releases before state was persisted leave stable memory empty, so there
+    // is nothing to restore. Only the jobs are started again.
+    if ic_cdk::api::stable::stable_size() == 0 {
+        jobs::start();
+        return;
+    }
+
+    let (state,): (StableState,) =
+        ic_cdk::storage::stable_restore().expect("failed to restore state from stable memory");
+    if let Err(e) = check_version(state.version) {
+        ic_cdk::trap(&e);
+    }
+
+    CUSTOMERS.with_borrow_mut(|customers| **customers = state.customers);
+    SHIPMENTS.with_borrow_mut(|shipments| **shipments = state.shipments);
+    CARRIERS.with_borrow_mut(|carriers| **carriers = state.carriers);
+    SHIPMENT_COUNTER.set(state.shipment_counter);
+    TRAILS.set(state.trails);
+    SLA_CONFIG.set(state.sla_config);
+    EXPIRY_CONFIG.set(state.expiry_config);
+    DISPUTE_CONFIG.set(state.dispute_config);
+    PRICING_CONFIG.set(state.pricing_config);
+    QUOTA_CONFIG.set(state.quota_config);
+    USAGE.set(state.usage);
+    FEE_CONFIG.set(state.fee_config);
+    FEE_LEDGER.set(state.fee_ledger);
+    GEOFENCE_CONFIG.set(state.geofence_config);
+    COLLATERAL_CONFIG.set(state.collateral_config);
+    MODE.set(state.mode);
+    EVENTS.set(state.events);
+    LAST_SEQUENCE.set(state.last_sequence);
+    ADMINS.set(state.admins);
+    ARBITERS.set(state.arbiters);
+    AUDITORS.set(state.auditors);
+    BANNED.set(state.banned);
+
+    jobs::start();
+}
+
+/// Snapshots from a newer layout would lose fields this code does not know,
+/// the upgrade fails instead so that the newer code can be reinstalled.
+fn check_version(version: u32) -> Result<(), String> {
+    match version {
+        version if version > STATE_VERSION => Err(format!(
+            "cannot restore state version {version}, this code reads up to {STATE_VERSION}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_newer_snapshots() {
+        assert!(check_version(STATE_VERSION).is_ok());
+        assert!(check_version(STATE_VERSION + 1).is_err());
+    }
+}